@@ -0,0 +1,305 @@
+//! MSVC command line quoting.
+//!
+//! Windows passes a child process a single command line string. The child's CRT
+//! (and `CommandLineToArgvW`) splits it back into `argv` by these rules:
+//!
+//! - Arguments are separated by spaces or tabs.
+//! - `"` starts and ends a quoted part, where spaces and tabs are literal.
+//! - `""` in a quoted part is a literal `"`.
+//! - `2n` backslashes followed by `"` are `n` backslashes, and the `"` quotes.
+//! - `2n + 1` backslashes followed by `"` are `n` backslashes and a literal `"`.
+//! - Backslashes not followed by `"` are literal.
+//!
+//! [`quote_arg`] and [`join_args`] build a command line [`split_args`] parses back
+//! into the same arguments. The program name (`argv[0]`) is parsed with simpler
+//! rules by the CRT, so it must not contain `"`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use winspawn::args::{join_args, split_args};
+//!
+//! let args = ["python", "-c", r#"print("a b")"#, ""];
+//! let line = join_args(args);
+//! assert_eq!(r#"python -c "print(\"a b\")" """#, line);
+//! assert_eq!(args.to_vec(), split_args(&line));
+//! ```
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::iter::{self, Peekable};
+
+const SPACE: u16 = b' ' as u16;
+const TAB: u16 = b'\t' as u16;
+const QUOTE: u16 = b'"' as u16;
+const BACKSLASH: u16 = b'\\' as u16;
+
+/// Argument of a command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Arg<T> {
+    /// Quoted as needed.
+    Regular(T),
+    /// Appended as is.
+    Raw(T),
+}
+
+impl<T> Arg<T> {
+    pub(crate) fn get(&self) -> &T {
+        match self {
+            Self::Regular(arg) | Self::Raw(arg) => arg,
+        }
+    }
+
+    pub(crate) fn map<U, F: FnOnce(&T) -> U>(&self, f: F) -> Arg<U> {
+        match self {
+            Self::Regular(arg) => Arg::Regular(f(arg)),
+            Self::Raw(arg) => Arg::Raw(f(arg)),
+        }
+    }
+}
+
+/// UTF-16 of `s`. Non-Unicode is replaced outside Windows.
+pub(crate) fn to_wide(s: &OsStr) -> Vec<u16> {
+    #[cfg(windows)]
+    return std::os::windows::ffi::OsStrExt::encode_wide(s).collect();
+    #[cfg(not(windows))]
+    return s.to_string_lossy().encode_utf16().collect();
+}
+
+/// Inverse of [`to_wide`].
+pub(crate) fn from_wide(s: &[u16]) -> OsString {
+    #[cfg(windows)]
+    return std::os::windows::ffi::OsStringExt::from_wide(s);
+    #[cfg(not(windows))]
+    return String::from_utf16_lossy(s).into();
+}
+
+/// `true` if `arg` must be quoted.
+fn needs_quote(arg: &[u16]) -> bool {
+    arg.is_empty()
+        || arg
+            .iter()
+            .any(|c| matches!(*c, SPACE | TAB | QUOTE) || *c == b'\n' as u16 || *c == 0x0b)
+}
+
+/// Append quoted `arg` to `out`.
+pub(crate) fn quote_arg_wide(arg: &[u16], out: &mut Vec<u16>) {
+    if !needs_quote(arg) {
+        out.extend_from_slice(arg);
+        return;
+    }
+
+    out.push(QUOTE);
+    let mut backslashes = 0;
+    for c in arg {
+        match *c {
+            BACKSLASH => backslashes += 1,
+            QUOTE => {
+                // escape the backslashes and the quote
+                out.extend(iter::repeat_n(BACKSLASH, backslashes * 2 + 1));
+                out.push(QUOTE);
+                backslashes = 0;
+            }
+            c => {
+                out.extend(iter::repeat_n(BACKSLASH, backslashes));
+                out.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    // not to escape the closing quote
+    out.extend(iter::repeat_n(BACKSLASH, backslashes * 2));
+    out.push(QUOTE);
+}
+
+/// Command line of `args`, each quoted and separated by a space.
+pub(crate) fn join_args_wide<I, A>(args: I) -> Vec<u16>
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<[u16]>,
+{
+    join_command_line_wide(args.into_iter().map(Arg::Regular))
+}
+
+/// Command line of `args` separated by a space. Only [`Arg::Regular`] are quoted.
+pub(crate) fn join_command_line_wide<I, A>(args: I) -> Vec<u16>
+where
+    I: IntoIterator<Item = Arg<A>>,
+    A: AsRef<[u16]>,
+{
+    let mut line = vec![];
+    for (i, arg) in args.into_iter().enumerate() {
+        if i > 0 {
+            line.push(SPACE);
+        }
+        match arg {
+            Arg::Regular(arg) => quote_arg_wide(arg.as_ref(), &mut line),
+            Arg::Raw(arg) => line.extend_from_slice(arg.as_ref()),
+        }
+    }
+    line
+}
+
+/// Split command line into arguments.
+pub(crate) fn split_args_wide(line: &[u16]) -> Vec<Vec<u16>> {
+    let mut args = vec![];
+    let mut iter = line.iter().copied().peekable();
+    loop {
+        while let Some(&(SPACE | TAB)) = iter.peek() {
+            iter.next();
+        }
+        if iter.peek().is_none() {
+            return args;
+        }
+
+        let mut arg = vec![];
+        let mut quoted = false;
+        let mut backslashes = 0;
+        while let Some(c) = iter.peek().copied() {
+            if c != BACKSLASH && backslashes > 0 && c != QUOTE {
+                arg.extend(iter::repeat_n(BACKSLASH, backslashes));
+                backslashes = 0;
+            }
+            match c {
+                BACKSLASH => backslashes += 1,
+                QUOTE => {
+                    arg.extend(iter::repeat_n(BACKSLASH, backslashes / 2));
+                    if backslashes % 2 == 1 {
+                        arg.push(QUOTE);
+                    } else if quoted && next_is_quote(&mut iter) {
+                        // `""` in quoted part
+                        arg.push(QUOTE);
+                    } else {
+                        quoted = !quoted;
+                    }
+                    backslashes = 0;
+                }
+                SPACE | TAB if !quoted => break,
+                c => arg.push(c),
+            }
+            iter.next();
+        }
+        arg.extend(iter::repeat_n(BACKSLASH, backslashes));
+        args.push(arg);
+    }
+}
+
+/// Consume the next `"` following the current one, if any.
+fn next_is_quote<I>(iter: &mut Peekable<I>) -> bool
+where
+    I: Iterator<Item = u16> + Clone,
+{
+    let mut ahead = iter.clone();
+    ahead.next(); // current
+    if ahead.next() == Some(QUOTE) {
+        iter.next();
+        true
+    } else {
+        false
+    }
+}
+
+/// Quote `arg` to be parsed back as a single argument.
+///
+/// Returned as is if no quoting needed.
+pub fn quote_arg(arg: &str) -> Cow<'_, str> {
+    let wide = arg.encode_utf16().collect::<Vec<_>>();
+    if !needs_quote(&wide) {
+        return Cow::Borrowed(arg);
+    }
+    let mut out = vec![];
+    quote_arg_wide(&wide, &mut out);
+    Cow::Owned(String::from_utf16(&out).unwrap())
+}
+
+/// Build a command line from `args`.
+pub fn join_args<I, A>(args: I) -> String
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<str>,
+{
+    let args = args
+        .into_iter()
+        .map(|arg| arg.as_ref().encode_utf16().collect::<Vec<_>>());
+    String::from_utf16(&join_args_wide(args)).unwrap()
+}
+
+/// Split a command line into arguments.
+pub fn split_args(line: &str) -> Vec<String> {
+    let line = line.encode_utf16().collect::<Vec<_>>();
+    split_args_wide(&line)
+        .into_iter()
+        .map(|arg| String::from_utf16(&arg).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!("abc", quote_arg("abc"));
+        assert_eq!(r#""""#, quote_arg(""));
+        assert_eq!(r#""a b""#, quote_arg("a b"));
+        assert_eq!(r#"a\b\"#, quote_arg(r#"a\b\"#));
+        assert_eq!(r#""a b\\""#, quote_arg(r#"a b\"#));
+        assert_eq!(r#""a\"b""#, quote_arg(r#"a"b"#));
+        assert_eq!(r#""a\\\"b""#, quote_arg(r#"a\"b"#));
+        assert_eq!("\"a\tb\"", quote_arg("a\tb"));
+    }
+
+    #[test]
+    fn test_split() {
+        // from the `CommandLineToArgvW` / "Parsing C++ command-line arguments" documentation
+        let cases: &[(&str, &[&str])] = &[
+            (r#""abc" d e"#, &["abc", "d", "e"]),
+            (r#"a\\b d"e f"g h"#, &[r#"a\\b"#, "de fg", "h"]),
+            (r#"a\\\"b c d"#, &[r#"a\"b"#, "c", "d"]),
+            (r#"a\\\\"b c" d e"#, &[r#"a\\b c"#, "d", "e"]),
+            (r#"a"b"" c d"#, &[r#"ab" c d"#]),
+            ("  \t ", &[]),
+            (r#""" """#, &["", ""]),
+            (r#"a\"#, &[r#"a\"#]),
+            (r#""a"#, &["a"]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(expected.to_vec(), split_args(line), "{}", line);
+        }
+    }
+
+    fn arg() -> impl Strategy<Value = String> {
+        prop_oneof!["[ \t\"\\\\ab]{0,8}", any::<String>()]
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip(args in prop::collection::vec(arg(), 0..8)) {
+            let line = join_args(&args);
+            prop_assert_eq!(args, split_args(&line));
+        }
+
+        #[test]
+        fn test_roundtrip_wide(args in prop::collection::vec(
+            prop::collection::vec(prop_oneof![Just(SPACE), Just(QUOTE), Just(BACKSLASH), any::<u16>()], 0..8),
+            0..8,
+        )) {
+            let line = join_args_wide(&args);
+            prop_assert_eq!(args, split_args_wide(&line));
+        }
+
+        #[test]
+        fn test_raw(arg in "[ \t\"\\\\ab]{0,8}") {
+            let line = join_command_line_wide([Arg::Regular(to_wide("x".as_ref())), Arg::Raw(to_wide(arg.as_ref()))]);
+            prop_assert_eq!(format!("x {}", arg), String::from_utf16(&line).unwrap());
+        }
+
+        #[test]
+        fn test_split_any(line in "[ \t\"\\\\ab]{0,16}") {
+            let args = split_args(&line);
+            prop_assert_eq!(&args, &split_args(&join_args(&args)));
+        }
+    }
+}
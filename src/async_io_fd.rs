@@ -0,0 +1,236 @@
+//! [`FileDescriptor`] for `async-io` based runtimes, such as smol and async-std.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::FileDescriptor;
+
+/// Asynchronous [`FileDescriptor`], such as the parent's end of a [`pipe`](crate::pipe).
+///
+/// Implements [`AsyncRead`] and [`AsyncWrite`] of `futures-io`, independent of any
+/// executor.
+///
+/// On Unix the fd is switched to nonblocking mode and polled by the `async-io` reactor.
+/// On Windows anonymous pipes can not be polled, so the handle of the fd
+/// (`_get_osfhandle`) is read and written on the thread pool of `blocking`.
+/// Flush before dropping to be sure written data is passed on.
+///
+/// # Example
+///
+/// ```rust
+/// # fn main() -> std::io::Result<()> {
+/// use futures_lite::{AsyncReadExt, AsyncWriteExt};
+/// use winspawn::{pipe, AsyncIoFd, PipeMode};
+///
+/// futures_lite::future::block_on(async {
+///     let (rx, tx) = pipe(PipeMode::new(), 0)?;
+///     let mut rx = AsyncIoFd::new(rx)?;
+///     let mut tx = AsyncIoFd::new(tx)?;
+///     tx.write_all(b"hello").await?;
+///     tx.close().await?;
+///     drop(tx);
+///
+///     let mut buf = String::new();
+///     rx.read_to_string(&mut buf).await?;
+///     assert_eq!("hello", buf);
+///     Ok(())
+/// })
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct AsyncIoFd(imp::Inner);
+
+impl AsyncIoFd {
+    /// Take ownership of `fd`.
+    pub fn new(fd: FileDescriptor) -> io::Result<Self> {
+        imp::Inner::new(fd).map(Self)
+    }
+}
+
+impl AsyncRead for AsyncIoFd {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncIoFd {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_close(cx)
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::os::unix::io::{AsFd, BorrowedFd};
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+
+    use async_io::Async;
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    use crate::FileDescriptor;
+
+    #[derive(Debug)]
+    struct Fd(FileDescriptor);
+
+    impl AsFd for Fd {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            // open while self is
+            unsafe { BorrowedFd::borrow_raw(self.0.as_raw_fd()) }
+        }
+    }
+
+    #[derive(Debug)]
+    pub(super) struct Inner(Async<Fd>);
+
+    impl Inner {
+        pub(super) fn new(fd: FileDescriptor) -> io::Result<Self> {
+            crate::unix::set_nonblocking(fd.as_raw_fd())?;
+            Async::new(Fd(fd)).map(Self)
+        }
+    }
+
+    impl AsyncRead for Inner {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            loop {
+                match self.0.get_ref().0.read(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return Poll::Ready(result),
+                }
+                ready!(self.0.poll_readable(cx))?;
+            }
+        }
+    }
+
+    impl AsyncWrite for Inner {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            loop {
+                match self.0.get_ref().0.write(buf) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return Poll::Ready(result),
+                }
+                ready!(self.0.poll_writable(cx))?;
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::fs::File;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use blocking::Unblock;
+    use futures_io::{AsyncRead, AsyncWrite};
+
+    use crate::FileDescriptor;
+
+    #[derive(Debug)]
+    pub(super) struct Inner(Unblock<File>);
+
+    impl Inner {
+        pub(super) fn new(fd: FileDescriptor) -> io::Result<Self> {
+            fd.into_file().map(|file| Self(Unblock::new(file)))
+        }
+    }
+
+    impl AsyncRead for Inner {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Inner {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_close(cx)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use futures_lite::{future, AsyncReadExt, AsyncWriteExt};
+
+    use crate::{Command, Stdio};
+
+    #[test]
+    fn test_child_pipes() {
+        let mut child = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = AsyncIoFd::new(child.stdin.take().unwrap().into_fd()).unwrap();
+        let mut stdout = AsyncIoFd::new(child.stdout.take().unwrap().into_fd()).unwrap();
+
+        // more than the pipe buffers, so both sides wait for readiness
+        let data = vec![b'x'; 1 << 20];
+        let write = async {
+            stdin.write_all(&data).await.unwrap();
+            stdin.close().await.unwrap();
+            drop(stdin);
+        };
+        let mut buf = vec![];
+        let read = stdout.read_to_end(&mut buf);
+        let ((), n) = future::block_on(future::zip(write, read));
+        assert_eq!(data.len(), n.unwrap());
+        assert_eq!(data, buf);
+        assert!(child.wait().unwrap().success());
+    }
+}
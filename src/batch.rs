@@ -0,0 +1,289 @@
+//! Command line for `.bat` / `.cmd` scripts.
+//!
+//! Windows runs a batch script with `cmd.exe /c`, which parses the command line
+//! again by its own rules: `&`, `|`, `<`, `>` and `^` outside quotes are operators,
+//! `%VAR%` is expanded even inside quotes, and a line break ends the command.
+//! Quoting by [`args`](crate::args) rules alone lets an argument run another command.
+//!
+//! [`batch_command_line`] quotes every argument that contains anything but a small
+//! set of safe characters, doubles `"` inside quotes, and defuses `%` by the
+//! `%%cd:~,%` substitution (an empty substring of `%cd%`). Arguments containing a
+//! line break or NUL can not be passed safely and are refused.
+//!
+//! # Example
+//!
+//! ```rust
+//! use winspawn::batch::{batch_command_line, is_batch_file};
+//!
+//! assert!(is_batch_file("build.CMD"));
+//! let line = batch_command_line("build.cmd", ["a&calc", "%PATH%"]).unwrap();
+//! assert_eq!(
+//!     r#"/e:ON /v:OFF /d /c ""build.cmd" "a&calc" "%%cd:~,%PATH%%cd:~,%"""#,
+//!     line
+//! );
+//! assert!(batch_command_line("build.cmd", ["a\nb"]).is_err());
+//! ```
+
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::path::Path;
+
+use crate::args::Arg;
+
+const QUOTE: u16 = b'"' as u16;
+const BACKSLASH: u16 = b'\\' as u16;
+const PERCENT: u16 = b'%' as u16;
+
+/// Arguments of `cmd.exe` before the script.
+const PREFIX: &str = "/e:ON /v:OFF /d /c \"";
+
+/// `cmd.exe` of the system. Not searched in `PATH` nor the current directory.
+pub(crate) fn comspec() -> OsString {
+    match env::var_os("SystemRoot") {
+        Some(mut root) => {
+            root.push(r"\System32\cmd.exe");
+            root
+        }
+        None => OsString::from("cmd.exe"),
+    }
+}
+
+/// `true` if `program` is a `.bat` or `.cmd` script.
+///
+/// Trailing dots and spaces are ignored as Windows does when opening a file.
+pub fn is_batch_file<P: AsRef<Path>>(program: P) -> bool {
+    let name = program.as_ref().to_string_lossy();
+    let name = name.trim_end_matches(['.', ' ']);
+    let len = name.len();
+    len >= 4
+        && name.is_char_boundary(len - 4)
+        && [".bat", ".cmd"]
+            .iter()
+            .any(|ext| name[len - 4..].eq_ignore_ascii_case(ext))
+}
+
+/// Argument (or script path) that can not be passed to a batch script safely.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchArgError {
+    arg: String,
+    reason: &'static str,
+}
+
+impl BatchArgError {
+    fn new(arg: &[u16], reason: &'static str) -> Self {
+        Self {
+            arg: String::from_utf16_lossy(arg),
+            reason,
+        }
+    }
+
+    /// The refused argument. (lossy if not Unicode)
+    pub fn arg(&self) -> &str {
+        &self.arg
+    }
+}
+
+impl fmt::Display for BatchArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unsafe batch file argument {:?}: {}",
+            self.arg, self.reason
+        )
+    }
+}
+
+impl Error for BatchArgError {}
+
+impl From<BatchArgError> for io::Error {
+    fn from(err: BatchArgError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// `true` if `c` is fine unquoted.
+fn is_safe(c: u16) -> bool {
+    match char::from_u32(c as u32) {
+        Some(c) if c.is_ascii() => c.is_ascii_alphanumeric() || r"#$*+-./:?@\_".contains(c),
+        Some(c) => !c.is_control(),
+        // surrogate
+        None => true,
+    }
+}
+
+/// Append `arg` escaped for `cmd.exe` and then the CRT of the script's programs.
+fn append_arg(arg: &[u16], out: &mut Vec<u16>) -> Result<(), BatchArgError> {
+    if let Some(c) = arg.iter().find(|c| matches!(**c, 0 | 0x0a | 0x0d)) {
+        let reason = if *c == 0 {
+            "contains NUL"
+        } else {
+            "contains line break"
+        };
+        return Err(BatchArgError::new(arg, reason));
+    }
+
+    // a trailing backslash would escape the closing quote of `"%~1"` in the script
+    let quote =
+        arg.is_empty() || arg.last() == Some(&BACKSLASH) || !arg.iter().all(|c| is_safe(*c));
+    if quote {
+        out.push(QUOTE);
+    }
+
+    let mut backslashes = 0;
+    for c in arg {
+        match *c {
+            BACKSLASH => backslashes += 1,
+            QUOTE => {
+                // `""` is a quote for both cmd.exe and the CRT
+                out.extend(iter::repeat_n(BACKSLASH, backslashes));
+                out.push(QUOTE);
+                backslashes = 0;
+            }
+            PERCENT => {
+                out.extend("%%cd:~,".encode_utf16());
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+        out.push(*c);
+    }
+
+    if quote {
+        out.extend(iter::repeat_n(BACKSLASH, backslashes));
+        out.push(QUOTE);
+    }
+    Ok(())
+}
+
+/// Command line after `cmd.exe` to run `script` with `args`.
+///
+/// [`Arg::Raw`] is appended as is.
+pub(crate) fn batch_command_line_wide<I, A>(
+    script: &[u16],
+    args: I,
+) -> Result<Vec<u16>, BatchArgError>
+where
+    I: IntoIterator<Item = Arg<A>>,
+    A: AsRef<[u16]>,
+{
+    if script.contains(&QUOTE) || script.last() == Some(&BACKSLASH) {
+        return Err(BatchArgError::new(
+            script,
+            "script path contains `\"` or ends with `\\`",
+        ));
+    }
+    if script
+        .iter()
+        .any(|c| matches!(*c, 0 | 0x0a | 0x0d | PERCENT))
+    {
+        return Err(BatchArgError::new(
+            script,
+            "script path contains `%`, line break or NUL",
+        ));
+    }
+
+    let mut line = PREFIX.encode_utf16().collect::<Vec<_>>();
+    line.push(QUOTE);
+    line.extend_from_slice(script);
+    line.push(QUOTE);
+    for arg in args {
+        line.push(b' ' as u16);
+        match arg {
+            Arg::Regular(arg) => append_arg(arg.as_ref(), &mut line)?,
+            Arg::Raw(arg) => line.extend_from_slice(arg.as_ref()),
+        }
+    }
+    // closes the quote of `/c "`
+    line.push(QUOTE);
+    Ok(line)
+}
+
+/// Arguments of `cmd.exe` to run `script` with `args`.
+pub fn batch_command_line<I, A>(script: &str, args: I) -> Result<String, BatchArgError>
+where
+    I: IntoIterator<Item = A>,
+    A: AsRef<str>,
+{
+    let script = script.encode_utf16().collect::<Vec<_>>();
+    let args = args
+        .into_iter()
+        .map(|arg| Arg::Regular(arg.as_ref().encode_utf16().collect::<Vec<_>>()));
+    let line = batch_command_line_wide(&script, args)?;
+    Ok(String::from_utf16(&line).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(args: &[&str]) -> Result<String, BatchArgError> {
+        batch_command_line("x.bat", args).map(|line| line[PREFIX.len()..line.len() - 1].to_string())
+    }
+
+    #[test]
+    fn test_is_batch_file() {
+        assert!(is_batch_file("a.bat"));
+        assert!(is_batch_file(r"C:\dir\a.CmD"));
+        assert!(is_batch_file("a.bat. ."));
+        assert!(!is_batch_file("a.exe"));
+        assert!(!is_batch_file("bat"));
+        assert!(!is_batch_file("a.batch"));
+        assert!(!is_batch_file("\u{3042}.ba"));
+    }
+
+    #[test]
+    fn test_plain() {
+        assert_eq!(
+            r#""x.bat" abc "C:\dir\\" -x"#,
+            line(&["abc", r"C:\dir\", "-x"]).unwrap()
+        );
+        assert_eq!(r#""x.bat" "" "a b""#, line(&["", "a b"]).unwrap());
+    }
+
+    #[test]
+    fn test_injection() {
+        // argument -> escaped
+        let cases = [
+            ("&calc", r#""&calc""#),
+            ("a|calc", r#""a|calc""#),
+            ("a>out", r#""a>out""#),
+            ("a<in", r#""a<in""#),
+            ("^&", r#""^&""#),
+            ("(calc)", r#""(calc)""#),
+            ("!PATH!", r#""!PATH!""#),
+            (r#"" & calc & ""#, r#"""" & calc & """"#),
+            (r#"\" & calc"#, r#""\\"" & calc""#),
+            (r#"\\"&calc&\\"#, r#""\\\\""&calc&\\\\""#),
+            ("%PATH%", r#""%%cd:~,%PATH%%cd:~,%""#),
+            ("%%", r#""%%cd:~,%%%cd:~,%""#),
+            ("a\tb", "\"a\tb\""),
+        ];
+        for (arg, expected) in cases {
+            assert_eq!(
+                format!(r#""x.bat" {}"#, expected),
+                line(&[arg]).unwrap(),
+                "{}",
+                arg
+            );
+        }
+    }
+
+    #[test]
+    fn test_refused() {
+        for arg in ["a\nb", "a\r\ncalc", "a\0b"] {
+            let err = line(&[arg]).unwrap_err();
+            assert_eq!(arg, err.arg());
+        }
+        assert!(batch_command_line(r#"a".bat"#, ["x"]).is_err());
+        assert!(batch_command_line(r"dir\", ["x"]).is_err());
+        assert!(batch_command_line("%x%.bat", ["x"]).is_err());
+
+        let err = io::Error::from(line(&["\n"]).unwrap_err());
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(err.get_ref().unwrap().is::<BatchArgError>());
+    }
+}
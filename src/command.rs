@@ -0,0 +1,583 @@
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::iter;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
+
+use std::io;
+
+use crate::args::{self, Arg};
+use crate::batch;
+use crate::environ::Env;
+use crate::resolve::resolve_program;
+use crate::stdio::{self, ChildStderr, ChildStdin, ChildStdout, Output, Stdio};
+use crate::{Child, DropPolicy, ExitStatus, FdMap, FileDescriptor};
+
+/// A process builder.
+///
+/// Modelled on [`std::process::Command`], with the addition of [`Command::fd`]
+/// for passing file descriptors to the child process.
+///
+/// # Example
+///
+/// ```rust
+/// use winspawn::Command;
+///
+/// let mut cmd = Command::new("python");
+/// cmd.arg("./tests/test.py").env("PYTHONUNBUFFERED", "1");
+/// assert_eq!(Some("1".as_ref()), cmd.get_envs().next().unwrap().1);
+/// ```
+#[derive(Debug)]
+pub struct Command<'a> {
+    program: OsString,
+    args: Vec<Arg<OsString>>,
+    env: Env,
+    current_dir: Option<PathBuf>,
+    fds: FdMap<'a>,
+    stdin: Option<Stdio>,
+    stdout: Option<Stdio>,
+    stderr: Option<Stdio>,
+    lock_free: bool,
+    resolve: bool,
+    drop_policy: DropPolicy,
+}
+
+impl<'a> Command<'a> {
+    /// Construct a new `Command` for launching `program`.
+    ///
+    /// By default the child inherits the parent's environment and current directory.
+    pub fn new<P: AsRef<OsStr>>(program: P) -> Self {
+        Self {
+            program: program.as_ref().to_owned(),
+            args: vec![],
+            env: Env::default(),
+            current_dir: None,
+            fds: FdMap::new(),
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            lock_free: false,
+            resolve: false,
+            drop_policy: DropPolicy::default(),
+        }
+    }
+
+    /// Add an argument.
+    ///
+    /// On Windows it is quoted as [`quote_arg`](crate::args::quote_arg) does, so the
+    /// child sees it as is. See also [`Command::raw_arg`].
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(Arg::Regular(arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Add an argument to the Windows command line as is, without quoting.
+    ///
+    /// For programs parsing the command line by their own rules. The caller is
+    /// responsible for the escaping, also for `cmd.exe` when the program is a batch script.
+    /// On Unix it is passed as a single argument like [`Command::arg`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use winspawn::Command;
+    ///
+    /// let mut cmd = Command::new("msiexec");
+    /// cmd.arg("/i").arg("a b.msi").raw_arg(r#"PROPERTY="a b""#);
+    /// assert_eq!(r#"msiexec /i "a b.msi" PROPERTY="a b""#, cmd.command_line().unwrap());
+    /// ```
+    pub fn raw_arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(Arg::Raw(arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Add multiple arguments.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Set an environment variable for the child process.
+    ///
+    /// On Windows names are case-insensitive, and the latest spelling is passed.
+    pub fn env<K, V>(&mut self, key: K, val: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env.set(key.as_ref(), val.as_ref());
+        self
+    }
+
+    /// Set multiple environment variables for the child process.
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        for (key, val) in vars {
+            self.env(key, val);
+        }
+        self
+    }
+
+    /// Remove an environment variable from the child process.
+    ///
+    /// On Windows names are case-insensitive: removing `path` removes `PATH`.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+        self.env.remove(key.as_ref());
+        self
+    }
+
+    /// Clear all environment variables. The child starts with an empty environment.
+    ///
+    /// On Windows the hidden current directories of drives (`=C:` etc.) are kept.
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.env.clear();
+        self
+    }
+
+    /// Set the working directory for the child process.
+    ///
+    /// The parent's working directory is never changed. A relative program path such as
+    /// `./bin/tool` is resolved against `dir`, on every platform.
+    ///
+    /// On Windows the child is created by `CreateProcessW` as [`Command::lock_free`] does,
+    /// but inherits every inheritable fd as the default does. The program is found as
+    /// [`Command::resolve`] does. Unlike the default:
+    ///
+    /// - Every fd is passed in binary mode.
+    /// - An fd is inherited if its `HANDLE` is inheritable. The CRT's own no-inherit flag
+    ///   can not be read, and differs only for an fd from `_open_osfhandle`.
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.current_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Pass `fd` to the child process as file descriptor `dest`.
+    ///
+    /// A later call with the same `dest` replaces the former.
+    pub fn fd(&mut self, dest: c_int, fd: &'a FileDescriptor) -> &mut Self {
+        self.fds.insert(dest, fd);
+        self
+    }
+
+    /// Caller must keep `src` open for `'a`.
+    pub(crate) fn fd_raw(&mut self, dest: c_int, src: c_int) -> &mut Self {
+        self.fds.insert_raw(dest, src);
+        self
+    }
+
+    /// Pass every descriptor in `fds` to the child process.
+    pub fn fds(&mut self, fds: &FdMap<'a>) -> &mut Self {
+        for (dest, src) in fds.iter() {
+            self.fd_raw(dest, src);
+        }
+        self
+    }
+
+    /// Configure the child's stdin (fd 0). Inherited by default, except for
+    /// [`Command::output`].
+    ///
+    /// The same as passing an fd to [`Command::fd`] with `dest` 0, which wins if both are set.
+    /// On Windows anything but [`Stdio::inherit`] spawns as with [`Command::current_dir`],
+    /// with the same differences to the default.
+    pub fn stdin<S: Into<Stdio>>(&mut self, stdio: S) -> &mut Self {
+        self.stdin = Some(stdio.into());
+        self
+    }
+
+    /// Configure the child's stdout (fd 1). Inherited by default, except for
+    /// [`Command::output`].
+    ///
+    /// The same as passing an fd to [`Command::fd`] with `dest` 1, which wins if both are set.
+    /// On Windows anything but [`Stdio::inherit`] spawns as with [`Command::current_dir`],
+    /// with the same differences to the default.
+    pub fn stdout<S: Into<Stdio>>(&mut self, stdio: S) -> &mut Self {
+        self.stdout = Some(stdio.into());
+        self
+    }
+
+    /// Configure the child's stderr (fd 2). Inherited by default, except for
+    /// [`Command::output`].
+    ///
+    /// The same as passing an fd to [`Command::fd`] with `dest` 2, which wins if both are set.
+    /// On Windows anything but [`Stdio::inherit`] spawns as with [`Command::current_dir`],
+    /// with the same differences to the default.
+    pub fn stderr<S: Into<Stdio>>(&mut self, stdio: S) -> &mut Self {
+        self.stderr = Some(stdio.into());
+        self
+    }
+
+    /// Spawn with `CreateProcessW`, passing the CRT fd table to the child directly.
+    ///
+    /// The parent's fd table is left untouched and the global lock is not taken,
+    /// so spawns from multiple threads do not serialise.
+    ///
+    /// Unlike the default, only fd 0, 1, 2 and fds added by [`Command::fd`] are inherited.
+    /// Every fd is passed in binary mode. The program is found as [`Command::resolve`] does.
+    ///
+    /// Has no effect on Unix, where the child's fds are set up after `fork` and the
+    /// parent's fd table is never touched.
+    pub fn lock_free(&mut self, enable: bool) -> &mut Self {
+        self.lock_free = enable;
+        self
+    }
+
+    /// Find the program by [`resolve_program`](crate::resolve::resolve_program) and spawn
+    /// its absolute path, instead of letting the CRT search.
+    ///
+    /// The search is in [`Command::current_dir`] if set, then the parent's `PATH`, as the
+    /// CRT does. Spawn fails with `NotFound` if nothing matches.
+    ///
+    /// Has no effect on Unix, where the program is searched as `execvp` does.
+    pub fn resolve(&mut self, enable: bool) -> &mut Self {
+        self.resolve = enable;
+        self
+    }
+
+    /// What dropping the spawned [`Child`] does. See [`Child::set_drop_policy`].
+    pub fn drop_policy(&mut self, policy: DropPolicy) -> &mut Self {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Spawn the child process.
+    ///
+    /// On Windows a `.bat` / `.cmd` program runs by `cmd.exe` with arguments escaped as
+    /// [`batch`](crate::batch) does. An argument that can not be escaped fails with
+    /// [`BatchArgError`](crate::batch::BatchArgError) as `InvalidInput`.
+    pub fn spawn(&mut self) -> io::Result<Child> {
+        self.spawn_with([Stdio::inherit(), Stdio::inherit(), Stdio::inherit()])
+    }
+
+    /// Run the child to exit, collecting its stdout and stderr.
+    ///
+    /// Unless set, stdin is [`Stdio::null`] and stdout and stderr are [`Stdio::piped`].
+    /// Both pipes are read at the same time, so a child filling one does not block.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use winspawn::Command;
+    ///
+    /// let output = Command::new("python").args(["-c", "print(1)"]).output().unwrap();
+    /// assert!(output.status.success());
+    /// assert_eq!("1", String::from_utf8(output.stdout).unwrap().trim());
+    /// ```
+    pub fn output(&mut self) -> io::Result<Output> {
+        self.spawn_with([Stdio::null(), Stdio::piped(), Stdio::piped()])?
+            .wait_with_output()
+    }
+
+    /// Run the child to exit and return its status.
+    ///
+    /// Unless set, the child inherits the parent's stdio.
+    pub fn status(&mut self) -> io::Result<ExitStatus> {
+        self.spawn()?.wait()
+    }
+
+    /// Spawn with `defaults` for stdio not set.
+    fn spawn_with(&mut self, defaults: [Stdio; 3]) -> io::Result<Child> {
+        let [stdin, stdout, stderr] = &defaults;
+        let mut prepared = stdio::Prepared::new([
+            self.stdin.as_ref().unwrap_or(stdin),
+            self.stdout.as_ref().unwrap_or(stdout),
+            self.stderr.as_ref().unwrap_or(stderr),
+        ])?;
+        let fds = prepared.fd_map(&self.fds);
+        #[cfg(unix)]
+        let mut child = crate::unix::spawn_command(self, &fds)?;
+        #[cfg(windows)]
+        let mut child = crate::win::spawn_command(self, &fds)?;
+        drop(fds);
+        // the child's ends are closed with `prepared`
+        let [stdin, stdout, stderr] = &mut prepared.parent;
+        child.stdin = stdin.take().map(ChildStdin::new);
+        child.stdout = stdout.take().map(ChildStdout::new);
+        child.stderr = stderr.take().map(ChildStderr::new);
+        child.set_drop_policy(self.drop_policy);
+        Ok(child)
+    }
+
+    /// Program passed to [`Command::new`].
+    pub fn get_program(&self) -> &OsStr {
+        &self.program
+    }
+
+    /// Arguments that will be passed to the program. Not including the program itself.
+    pub fn get_args(&self) -> impl Iterator<Item = &OsStr> {
+        self.args.iter().map(|arg| arg.get().as_os_str())
+    }
+
+    /// Command line the child process receives on Windows, including the program.
+    ///
+    /// A batch script runs by `cmd.exe`, so the line starts with its path.
+    /// Available on every platform for testing. Non-Unicode is replaced outside Windows.
+    pub fn command_line(&self) -> io::Result<OsString> {
+        let (_, line) = self.windows_command_line(false)?;
+        Ok(args::from_wide(&line))
+    }
+
+    /// Environment variables explicitly set or removed (`None`) for the child process.
+    pub fn get_envs(&self) -> impl Iterator<Item = (&OsStr, Option<&OsStr>)> {
+        self.env.iter()
+    }
+
+    /// Working directory for the child process.
+    pub fn get_current_dir(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    /// File descriptor mapping as `(dest, source)` pairs in `dest` order.
+    pub fn get_fds(&self) -> impl Iterator<Item = (c_int, c_int)> + '_ {
+        self.fds.iter()
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn is_lock_free(&self) -> bool {
+        self.lock_free
+    }
+
+    /// Argument vector including the program as `argv[0]`.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn argv(&self) -> Vec<&OsStr> {
+        iter::once(self.get_program())
+            .chain(self.get_args())
+            .collect()
+    }
+
+    /// Application and command line for Windows, not nul terminated.
+    ///
+    /// The application is `cmd.exe` for a batch script, the program found by
+    /// [`resolve_program`] if `resolve` or [`Command::resolve`], otherwise `None`
+    /// (searched by the program name). A name without extension is a batch script if
+    /// [`resolve_program`] finds one either way.
+    ///
+    /// `CreateProcessW` only appends `.exe` in its own search, so it always resolves.
+    pub(crate) fn windows_command_line(
+        &self,
+        resolve: bool,
+    ) -> io::Result<(Option<OsString>, Vec<u16>)> {
+        let resolved = if resolve || self.resolve {
+            Some(self.resolved_program()?)
+        } else {
+            None
+        };
+        // the CRT appends `.bat` and `.cmd` by itself, so look at what it would run
+        let found = match &resolved {
+            Some(resolved) => Some(resolved.clone()),
+            None => self.resolved_program().ok(),
+        };
+        let program = found.as_deref().unwrap_or(Path::new(&self.program));
+        let args = self
+            .args
+            .iter()
+            .map(|arg| arg.map(|arg| args::to_wide(arg)));
+
+        if !batch::is_batch_file(program) {
+            let argv0 = Arg::Regular(args::to_wide(&self.program));
+            let line = args::join_command_line_wide(iter::once(argv0).chain(args));
+            return Ok((resolved.map(PathBuf::into_os_string), line));
+        }
+
+        let comspec = batch::comspec();
+        let mut line = vec![];
+        args::quote_arg_wide(&args::to_wide(&comspec), &mut line);
+        line.push(b' ' as u16);
+        let script = args::to_wide(program.as_os_str());
+        line.extend(batch::batch_command_line_wide(&script, args)?);
+        Ok((Some(comspec), line))
+    }
+
+    /// Absolute path of the program by [`resolve_program`].
+    fn resolved_program(&self) -> io::Result<PathBuf> {
+        let mut cwd = env::current_dir()?;
+        if let Some(dir) = &self.current_dir {
+            cwd.push(dir);
+        }
+        resolve_program(&self.program, env::var_os("PATH").as_deref(), None, &cwd)
+    }
+
+    /// Environment for the child process.
+    ///
+    /// `None` if the parent's environment is inherited unchanged.
+    pub(crate) fn capture_env(&self) -> Option<Vec<(OsString, OsString)>> {
+        if self.env.is_unchanged() {
+            None
+        } else {
+            Some(self.env.capture(env::vars_os()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(v: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        v.iter().map(|(k, v)| (k.into(), v.into())).collect()
+    }
+
+    #[test]
+    fn test_argv() {
+        let mut cmd = Command::new("python");
+        cmd.arg("-c").args(["print(1)", ""]);
+        assert_eq!(vec!["python", "-c", "print(1)", ""], cmd.argv());
+    }
+
+    #[test]
+    fn test_command_line() {
+        let mut cmd = Command::new("python");
+        cmd.arg("-c").arg("print(1, 2)").raw_arg(r#""a"b"#).arg("");
+        assert_eq!(
+            r#"python -c "print(1, 2)" "a"b """#,
+            cmd.command_line().unwrap()
+        );
+
+        let mut cmd = Command::new("run.bat");
+        cmd.arg("a&b").raw_arg("^&c");
+        let line = cmd.command_line().unwrap().into_string().unwrap();
+        assert!(line.ends_with(r#"/c ""run.bat" "a&b" ^&c""#), "{}", line);
+
+        cmd.arg("\n");
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            cmd.command_line().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_batch_without_extension() {
+        let tmp = tempfile::tempdir().unwrap();
+        let script = tmp.path().join("build.bat");
+        std::fs::write(&script, "").unwrap();
+
+        let mut cmd = Command::new("build");
+        cmd.current_dir(tmp.path()).arg("&calc");
+        let (application, line) = cmd.windows_command_line(false).unwrap();
+        assert_eq!(Some(batch::comspec()), application);
+        let line = args::from_wide(&line).into_string().unwrap();
+        let expected = format!(r#"/c ""{}" "&calc"""#, script.display());
+        assert!(line.ends_with(&expected), "{}", line);
+    }
+
+    #[test]
+    fn test_current_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmp.path().join("bin")).unwrap();
+        std::fs::write(tmp.path().join("bin/tool.cmd"), "").unwrap();
+
+        let mut cmd = Command::new("./bin/tool");
+        assert_eq!(None, cmd.windows_command_line(false).unwrap().0);
+
+        // found in the directory with the CRT's extensions
+        cmd.current_dir(tmp.path());
+        let (application, line) = cmd.windows_command_line(true).unwrap();
+        assert_eq!(Some(batch::comspec()), application);
+        let line = args::from_wide(&line).into_string().unwrap();
+        let script = tmp.path().join("./bin/tool.cmd");
+        assert!(
+            line.ends_with(&format!(r#"/c ""{}"""#, script.display())),
+            "{}",
+            line
+        );
+
+        let mut cmd = Command::new("no-such-program");
+        cmd.current_dir(tmp.path());
+        assert_eq!(None, cmd.windows_command_line(false).unwrap().0);
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            cmd.windows_command_line(true).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("tool.bat"), "").unwrap();
+        std::fs::write(tmp.path().join("tool.exe"), "").unwrap();
+        std::fs::write(tmp.path().join("other.exe"), "").unwrap();
+
+        let mut cmd = Command::new("other");
+        cmd.current_dir(tmp.path()).arg("a b");
+        assert_eq!(None, cmd.windows_command_line(false).unwrap().0);
+
+        cmd.resolve(true);
+        let (application, line) = cmd.windows_command_line(false).unwrap();
+        assert_eq!(
+            Some(tmp.path().join("other.exe").into_os_string()),
+            application
+        );
+        assert_eq!(r#"other "a b""#, args::from_wide(&line));
+
+        // `.bat` comes after `.exe`
+        let mut cmd = Command::new("tool");
+        cmd.current_dir(tmp.path()).resolve(true);
+        let (application, _) = cmd.windows_command_line(false).unwrap();
+        assert_eq!(
+            Some(tmp.path().join("tool.exe").into_os_string()),
+            application
+        );
+
+        let mut cmd = Command::new("tool.bat");
+        cmd.current_dir(tmp.path()).resolve(true);
+        let (application, line) = cmd.windows_command_line(false).unwrap();
+        assert_eq!(Some(batch::comspec()), application);
+        let line = args::from_wide(&line).into_string().unwrap();
+        let script = tmp.path().join("tool.bat");
+        assert!(
+            line.ends_with(&format!(r#"/c ""{}"""#, script.display())),
+            "{}",
+            line
+        );
+
+        let mut cmd = Command::new("no-such-program");
+        cmd.current_dir(tmp.path()).resolve(true);
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            cmd.windows_command_line(false).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_env() {
+        let base = vars(&[("A", "1"), ("B", "2")]);
+
+        let mut cmd = Command::new("python");
+        assert_eq!(None, cmd.capture_env());
+
+        cmd.env("C", "3").env_remove("A").env("B", "x");
+        assert_eq!(
+            vars(&[("B", "x"), ("C", "3")]),
+            cmd.env.capture(base.clone())
+        );
+
+        cmd.env_clear().env("D", "4").env_remove("B");
+        assert_eq!(vars(&[("D", "4")]), cmd.env.capture(base.clone()));
+        assert_eq!(Some(vars(&[("D", "4")])), cmd.capture_env());
+
+        if cfg!(windows) {
+            let mut cmd = Command::new("python");
+            cmd.env("Path", "x").env_remove("a");
+            assert_eq!(
+                vec![("B".into(), "2".into()), ("Path".into(), "x".into())],
+                cmd.env.capture(base)
+            );
+        }
+    }
+
+    #[test]
+    fn test_fds() {
+        let mut cmd = Command::new("python");
+        cmd.fd_raw(4, 10).fd_raw(3, 11).fd_raw(4, 12);
+        assert_eq!(vec![(3, 11), (4, 12)], cmd.get_fds().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,297 @@
+//! Spawn without touching the parent's fd table.
+//!
+//! Instead of moving fds into place around `_wspawnvp` under the global lock, the
+//! CRT fd-inheritance block (see [`reserved2`](crate::reserved2)) is built for the
+//! child and passed to `CreateProcessW` together with an explicit handle list.
+//! The OS specific part is behind [`Backend`].
+#![cfg_attr(not(windows), allow(dead_code))]
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::os::raw::c_int;
+use std::path::Path;
+
+use crate::reserved2::{self, Entry, Flags, INVALID_HANDLE};
+use crate::{Command, FdMap};
+
+/// Everything the child process is created with.
+#[derive(Debug)]
+pub(crate) struct Startup<'a> {
+    /// `lpApplicationName`. `None` if searched by the command line.
+    pub(crate) application: Option<OsString>,
+    /// Not nul terminated.
+    pub(crate) command_line: Vec<u16>,
+    /// `None` if inherits the parent's environment.
+    pub(crate) env: Option<Vec<(OsString, OsString)>>,
+    pub(crate) current_dir: Option<&'a Path>,
+    /// `lpReserved2`
+    pub(crate) reserved2: Vec<u8>,
+    /// Inheritable handles. Nothing else is inherited.
+    pub(crate) handles: Vec<isize>,
+    /// Handles of fd 0, 1 and 2. (`hStdInput`, `hStdOutput`, `hStdError`)
+    pub(crate) stdio: [isize; 3],
+}
+
+pub(crate) trait Backend {
+    type Process;
+
+    /// Every open fd of the parent.
+    fn open_fds(&mut self) -> io::Result<Vec<c_int>>;
+
+    /// Flags and `HANDLE` of the parent's fd. `None` if not open.
+    fn query(&mut self, fd: c_int) -> io::Result<Option<(Flags, isize)>>;
+
+    /// Duplicate `handle` as inheritable.
+    fn duplicate_inheritable(&mut self, handle: isize) -> io::Result<isize>;
+
+    /// Close a handle from [`Backend::duplicate_inheritable`].
+    fn close(&mut self, handle: isize);
+
+    fn create_process(&mut self, startup: &Startup<'_>) -> io::Result<Self::Process>;
+}
+
+/// Which of the parent's fds the child inherits, besides the mapped ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Inherit {
+    /// fd 0, 1 and 2.
+    Stdio,
+    /// Every fd without [`Flags::FNOINHERIT`], as `_wspawnvp` does.
+    All,
+}
+
+/// Spawn `cmd` with `backend`.
+///
+/// The child inherits the parent's fds by `inherit` unless mapped, and every fd in
+/// `fds`. Text mode of fds is not passed on. The program is found by
+/// [`resolve_program`](crate::resolve::resolve_program), as `_wspawnvp` would.
+pub(crate) fn spawn<B: Backend>(
+    backend: &mut B,
+    cmd: &Command<'_>,
+    fds: &FdMap<'_>,
+    inherit: Inherit,
+) -> io::Result<B::Process> {
+    let mut dups = vec![];
+    let result = spawn_with(backend, cmd, fds, inherit, &mut dups);
+    for handle in dups {
+        backend.close(handle);
+    }
+    result
+}
+
+fn spawn_with<B: Backend>(
+    backend: &mut B,
+    cmd: &Command<'_>,
+    fds: &FdMap<'_>,
+    inherit: Inherit,
+    dups: &mut Vec<isize>,
+) -> io::Result<B::Process> {
+    // child fd -> parent fd
+    let mut layout = (0..3).map(|fd| (fd, fd)).collect::<BTreeMap<_, _>>();
+    if inherit == Inherit::All {
+        for fd in backend.open_fds()? {
+            if let Some((flags, _)) = backend.query(fd)? {
+                if !flags.contains(Flags::FNOINHERIT) {
+                    layout.insert(fd, fd);
+                }
+            }
+        }
+    }
+    layout.extend(fds.iter());
+
+    let mut entries = vec![];
+    for (dest, src) in layout {
+        let (flags, handle) = match backend.query(src)? {
+            Some(found) => found,
+            // parent has no such stdio
+            None if fds.iter().all(|(d, _)| d != dest) => continue,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("fd {} is not open", src),
+                ))
+            }
+        };
+
+        let dup = backend.duplicate_inheritable(handle)?;
+        dups.push(dup);
+
+        let flags = Flags::from_bits(flags.bits() & !Flags::FNOINHERIT.bits()) | Flags::FOPEN;
+        entries.push(Entry {
+            fd: dest,
+            flags,
+            handle: dup,
+        });
+    }
+
+    let mut stdio = [INVALID_HANDLE; 3];
+    for entry in &entries {
+        if let Some(slot) = stdio.get_mut(entry.fd as usize) {
+            *slot = entry.handle;
+        }
+    }
+
+    let (application, command_line) = cmd.windows_command_line(true)?;
+    let startup = Startup {
+        application,
+        command_line,
+        env: cmd.capture_env(),
+        current_dir: cmd.get_current_dir(),
+        reserved2: reserved2::encode(&entries)?,
+        handles: dups.clone(),
+        stdio,
+    };
+    log::trace!("create process {:?}", startup);
+    backend.create_process(&startup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    #[derive(Debug, Default)]
+    struct Fake {
+        // parent fd table
+        fds: HashMap<c_int, (Flags, isize)>,
+        fail_create: bool,
+        open: Vec<isize>,
+        next: isize,
+        created: Option<(Vec<Entry>, Vec<isize>, [isize; 3])>,
+    }
+
+    impl Fake {
+        fn new(fds: &[(c_int, Flags, isize)]) -> Self {
+            Self {
+                fds: fds.iter().map(|(fd, f, h)| (*fd, (*f, *h))).collect(),
+                next: 0x1000,
+                ..Self::default()
+            }
+        }
+    }
+
+    impl Backend for Fake {
+        type Process = ();
+
+        fn open_fds(&mut self) -> io::Result<Vec<c_int>> {
+            Ok(self.fds.keys().copied().collect())
+        }
+
+        fn query(&mut self, fd: c_int) -> io::Result<Option<(Flags, isize)>> {
+            Ok(self.fds.get(&fd).copied())
+        }
+
+        fn duplicate_inheritable(&mut self, _: isize) -> io::Result<isize> {
+            self.next += 4;
+            self.open.push(self.next);
+            Ok(self.next)
+        }
+
+        fn close(&mut self, handle: isize) {
+            self.open.retain(|h| *h != handle);
+        }
+
+        fn create_process(&mut self, startup: &Startup<'_>) -> io::Result<()> {
+            if self.fail_create {
+                return Err(io::Error::other("fail"));
+            }
+            let entries = reserved2::decode(&startup.reserved2)?;
+            self.created = Some((entries, startup.handles.clone(), startup.stdio));
+            Ok(())
+        }
+    }
+
+    const FOPEN: Flags = Flags::FOPEN;
+
+    /// A program found on every platform.
+    fn command() -> Command<'static> {
+        Command::new(std::env::current_exe().unwrap())
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut fake = Fake::new(&[
+            (0, FOPEN | Flags::FDEV, 0x10),
+            (1, FOPEN | Flags::FDEV, 0x14),
+            (2, FOPEN | Flags::FDEV, 0x18),
+            (5, FOPEN | Flags::FPIPE | Flags::FNOINHERIT, 0x20),
+            (6, FOPEN | Flags::FPIPE, 0x24),
+        ]);
+
+        let cmd = command();
+        let mut fds = FdMap::new();
+        fds.insert_raw(3, 5);
+        fds.insert_raw(4, 6);
+        fds.insert_raw(1, 6);
+        spawn(&mut fake, &cmd, &fds, Inherit::Stdio).unwrap();
+
+        let (entries, handles, stdio) = fake.created.unwrap();
+        let dev = FOPEN | Flags::FDEV;
+        let pipe = FOPEN | Flags::FPIPE;
+        assert_eq!(
+            vec![(0, dev), (1, pipe), (2, dev), (3, pipe), (4, pipe)],
+            entries.iter().map(|e| (e.fd, e.flags)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            handles,
+            entries.iter().map(|e| e.handle).collect::<Vec<_>>()
+        );
+        assert_eq!([handles[0], handles[1], handles[2]], stdio);
+        assert!(fake.open.is_empty(), "leaked {:?}", fake.open);
+    }
+
+    #[test]
+    fn test_inherit_all() {
+        let mut fake = Fake::new(&[
+            (0, FOPEN | Flags::FDEV, 0x10),
+            (4, FOPEN | Flags::FPIPE | Flags::FNOINHERIT, 0x20),
+            (7, FOPEN, 0x24),
+            (9, FOPEN | Flags::FNOINHERIT, 0x28),
+        ]);
+
+        let cmd = command();
+        let mut fds = FdMap::new();
+        fds.insert_raw(3, 9);
+        spawn(&mut fake, &cmd, &fds, Inherit::All).unwrap();
+
+        let (entries, _, _) = fake.created.unwrap();
+        assert_eq!(
+            vec![0, 3, 7],
+            entries.iter().map(|e| e.fd).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_no_stdio() {
+        let mut fake = Fake::new(&[(1, FOPEN, 0x10)]);
+        spawn(&mut fake, &command(), &FdMap::new(), Inherit::Stdio).unwrap();
+
+        let (entries, _, stdio) = fake.created.unwrap();
+        assert_eq!(vec![1], entries.iter().map(|e| e.fd).collect::<Vec<_>>());
+        assert_eq!([INVALID_HANDLE, entries[0].handle, INVALID_HANDLE], stdio);
+    }
+
+    #[test]
+    fn test_not_open() {
+        let mut fake = Fake::new(&[(0, FOPEN, 0x10), (5, FOPEN, 0x20)]);
+        let cmd = command();
+        let mut fds = FdMap::new();
+        fds.insert_raw(3, 5);
+        fds.insert_raw(4, 7);
+        assert!(spawn(&mut fake, &cmd, &fds, Inherit::Stdio).is_err());
+        assert!(fake.created.is_none());
+        assert!(fake.open.is_empty(), "leaked {:?}", fake.open);
+    }
+
+    #[test]
+    fn test_create_failed() {
+        let mut fake = Fake::new(&[(0, FOPEN, 0x10), (5, FOPEN, 0x20)]);
+        fake.fail_create = true;
+        let cmd = command();
+        let mut fds = FdMap::new();
+        fds.insert_raw(3, 5);
+        assert!(spawn(&mut fake, &cmd, &fds, Inherit::Stdio).is_err());
+        assert!(fake.open.is_empty(), "leaked {:?}", fake.open);
+    }
+}
@@ -0,0 +1,268 @@
+//! Environment of a child process.
+//!
+//! On Windows variable names are case-insensitive: setting `Path` replaces an
+//! inherited `PATH`. The environment also holds hidden entries like `=C:=C:\dir`,
+//! the current directory of each drive, whose names start with `=`.
+//! `CreateProcessW` takes the environment as a block of `NAME=value\0` entries,
+//! sorted case-insensitively by name and terminated by another `\0`.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+
+use crate::args;
+
+/// Variable name. Compared case-insensitively by Windows rules if `folded` is present.
+#[derive(Debug, Clone)]
+pub(crate) struct EnvKey {
+    name: OsString,
+    // uppercase UTF-16
+    folded: Option<Vec<u16>>,
+}
+
+impl EnvKey {
+    pub(crate) fn new(name: &OsStr, ignore_case: bool) -> Self {
+        let folded = if ignore_case {
+            Some(fold(&args::to_wide(name)))
+        } else {
+            None
+        };
+        Self {
+            name: name.to_owned(),
+            folded,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// `=C:` etc.
+    fn is_drive_cwd(&self) -> bool {
+        let name = args::to_wide(&self.name);
+        name.len() == 3
+            && name[0] == b'=' as u16
+            && (name[1] as u8 as u16 == name[1] && (name[1] as u8).is_ascii_alphabetic())
+            && name[2] == b':' as u16
+    }
+}
+
+/// Uppercase each UTF-16 unit that maps to a single unit, as `CompareStringOrdinal` does.
+fn fold(name: &[u16]) -> Vec<u16> {
+    name.iter()
+        .map(|c| {
+            let upper = char::from_u32(*c as u32).map(|c| {
+                let mut upper = c.to_uppercase();
+                match (upper.next(), upper.next()) {
+                    (Some(u), None) if (u as u32) <= 0xFFFF => u as u16,
+                    _ => c as u16,
+                }
+            });
+            upper.unwrap_or(*c)
+        })
+        .collect()
+}
+
+impl PartialEq for EnvKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for EnvKey {}
+
+impl PartialOrd for EnvKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for EnvKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (&self.folded, &other.folded) {
+            (Some(a), Some(b)) => a.cmp(b),
+            _ => self.name.cmp(&other.name),
+        }
+    }
+}
+
+/// Changes to the parent's environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Env {
+    ignore_case: bool,
+    clear: bool,
+    vars: BTreeMap<EnvKey, Option<OsString>>,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::new(cfg!(windows))
+    }
+}
+
+impl Env {
+    /// `ignore_case` for Windows semantics.
+    pub(crate) fn new(ignore_case: bool) -> Self {
+        Self {
+            ignore_case,
+            clear: false,
+            vars: BTreeMap::new(),
+        }
+    }
+
+    fn key(&self, name: &OsStr) -> EnvKey {
+        EnvKey::new(name, self.ignore_case)
+    }
+
+    fn survives_clear(&self, key: &EnvKey) -> bool {
+        self.ignore_case && key.is_drive_cwd()
+    }
+
+    pub(crate) fn set(&mut self, name: &OsStr, val: &OsStr) {
+        let key = self.key(name);
+        // the latest spelling of the name wins
+        self.vars.remove(&key);
+        self.vars.insert(key, Some(val.to_owned()));
+    }
+
+    pub(crate) fn remove(&mut self, name: &OsStr) {
+        let key = self.key(name);
+        self.vars.remove(&key);
+        if !self.clear || self.survives_clear(&key) {
+            self.vars.insert(key, None);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.clear = true;
+        self.vars.clear();
+    }
+
+    pub(crate) fn is_unchanged(&self) -> bool {
+        !self.clear && self.vars.is_empty()
+    }
+
+    /// Explicitly set or removed (`None`) variables.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&OsStr, Option<&OsStr>)> {
+        self.vars.iter().map(|(k, v)| (k.name(), v.as_deref()))
+    }
+
+    /// Apply changes to `base`, ordered by name.
+    ///
+    /// With Windows semantics the order is the one of the environment block, and
+    /// the drive current directories survive [`Env::clear`] unless removed.
+    pub(crate) fn capture<I>(&self, base: I) -> Vec<(OsString, OsString)>
+    where
+        I: IntoIterator<Item = (OsString, OsString)>,
+    {
+        let mut result = BTreeMap::new();
+        for (name, val) in base {
+            let key = self.key(&name);
+            if !self.clear || self.survives_clear(&key) {
+                result.insert(key, val);
+            }
+        }
+        for (key, val) in &self.vars {
+            result.remove(key);
+            if let Some(val) = val {
+                result.insert(key.clone(), val.clone());
+            }
+        }
+        result.into_iter().map(|(k, v)| (k.name, v)).collect()
+    }
+}
+
+/// Environment block for `CreateProcessW` with `CREATE_UNICODE_ENVIRONMENT`.
+///
+/// `vars` must be ordered as [`Env::capture`] does with Windows semantics.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub(crate) fn env_block(vars: &[(OsString, OsString)]) -> Vec<u16> {
+    let mut block = vec![];
+    for (name, val) in vars {
+        block.extend(args::to_wide(name));
+        block.push(b'=' as u16);
+        block.extend(args::to_wide(val));
+        block.push(0);
+    }
+    if block.is_empty() {
+        // an empty block still needs two NULs
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(v: &[(&str, &str)]) -> Vec<(OsString, OsString)> {
+        v.iter().map(|(k, v)| (k.into(), v.into())).collect()
+    }
+
+    #[test]
+    fn test_case_sensitive() {
+        let mut env = Env::new(false);
+        env.set("path".as_ref(), "x".as_ref());
+        assert_eq!(
+            vars(&[("PATH", "a"), ("path", "x")]),
+            env.capture(vars(&[("PATH", "a")]))
+        );
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let base = vars(&[
+            ("PATH", r"C:\bin"),
+            ("=C:", r"C:\dir"),
+            ("windir", r"C:\Windows"),
+            ("=D:", r"D:\"),
+        ]);
+
+        let mut env = Env::new(true);
+        env.set("Path".as_ref(), r"C:\other".as_ref());
+        env.set("aa".as_ref(), "1".as_ref());
+        env.set("AA".as_ref(), "2".as_ref());
+        env.remove("WINDIR".as_ref());
+        assert_eq!(
+            vars(&[
+                ("=C:", r"C:\dir"),
+                ("=D:", r"D:\"),
+                ("AA", "2"),
+                ("Path", r"C:\other"),
+            ]),
+            env.capture(base.clone())
+        );
+
+        let mut env = Env::new(true);
+        env.clear();
+        env.set("x".as_ref(), "1".as_ref());
+        env.remove("=d:".as_ref());
+        assert_eq!(vars(&[("=C:", r"C:\dir"), ("x", "1")]), env.capture(base));
+    }
+
+    #[test]
+    fn test_order() {
+        // `_` (0x5F) sorts after uppercase letters, before lowercase
+        let mut env = Env::new(true);
+        for name in ["b", "_a", "A", "\u{e9}", "Z"] {
+            env.set(name.as_ref(), "".as_ref());
+        }
+        let names = env
+            .capture(vec![])
+            .into_iter()
+            .map(|(k, _)| k.into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["A", "b", "Z", "_a", "\u{e9}"], names);
+    }
+
+    #[test]
+    fn test_block() {
+        let block = env_block(&vars(&[("=C:", r"C:\"), ("A", "1")]));
+        assert_eq!(
+            "=C:=C:\\\0A=1\0\0".encode_utf16().collect::<Vec<_>>(),
+            block
+        );
+        assert_eq!(vec![0, 0], env_block(&[]));
+    }
+}
@@ -0,0 +1,241 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::os::raw::c_int;
+
+use crate::invalid::InvalidParameterReport;
+
+// UCRT `errno.h`. (the same values on Unix)
+const ENOENT: c_int = 2;
+const E2BIG: c_int = 7;
+const ENOEXEC: c_int = 8;
+const EBADF: c_int = 9;
+const ENOMEM: c_int = 12;
+const EACCES: c_int = 13;
+const EINVAL: c_int = 22;
+const EMFILE: c_int = 24;
+
+/// Failure of a CRT call, decoded from `errno`.
+///
+/// UCRT functions such as `_dup2` and `_wspawnvp` report by `errno`, not by
+/// `GetLastError`. Returned inside [`io::Error`]; get it back with
+/// [`io::Error::get_ref`] and `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// `ENOENT`: the program (or a file) does not exist.
+    ProgramNotFound { op: &'static str, fd: Option<c_int> },
+    /// `EMFILE`: no more file descriptors.
+    TooManyOpenFiles { op: &'static str, fd: Option<c_int> },
+    /// `EBADF`: the file descriptor is not open.
+    BadDescriptor { op: &'static str, fd: Option<c_int> },
+    /// `E2BIG`: the argument list or environment is too long.
+    ArgumentListTooLong { op: &'static str, fd: Option<c_int> },
+    /// `ENOEXEC`: the program is not an executable.
+    ExecFormat { op: &'static str, fd: Option<c_int> },
+    /// `EACCES`: access denied.
+    PermissionDenied { op: &'static str, fd: Option<c_int> },
+    /// `ENOMEM`: out of memory.
+    OutOfMemory { op: &'static str, fd: Option<c_int> },
+    /// `EINVAL`: invalid argument.
+    InvalidArgument { op: &'static str, fd: Option<c_int> },
+    /// The CRT called the invalid parameter handler, such as for a closed fd.
+    InvalidParameter {
+        op: &'static str,
+        fd: Option<c_int>,
+        errno: c_int,
+        report: InvalidParameterReport,
+    },
+    /// Any other `errno`.
+    Other {
+        op: &'static str,
+        fd: Option<c_int>,
+        errno: c_int,
+    },
+}
+
+impl Error {
+    /// Error of `op` that failed with `errno`, involving `fd`.
+    pub fn from_errno(errno: c_int, op: &'static str, fd: Option<c_int>) -> Self {
+        match errno {
+            ENOENT => Self::ProgramNotFound { op, fd },
+            EMFILE => Self::TooManyOpenFiles { op, fd },
+            EBADF => Self::BadDescriptor { op, fd },
+            E2BIG => Self::ArgumentListTooLong { op, fd },
+            ENOEXEC => Self::ExecFormat { op, fd },
+            EACCES => Self::PermissionDenied { op, fd },
+            ENOMEM => Self::OutOfMemory { op, fd },
+            EINVAL => Self::InvalidArgument { op, fd },
+            errno => Self::Other { op, fd, errno },
+        }
+    }
+
+    /// The `errno` this error was decoded from.
+    pub fn errno(&self) -> c_int {
+        match self {
+            Self::ProgramNotFound { .. } => ENOENT,
+            Self::TooManyOpenFiles { .. } => EMFILE,
+            Self::BadDescriptor { .. } => EBADF,
+            Self::ArgumentListTooLong { .. } => E2BIG,
+            Self::ExecFormat { .. } => ENOEXEC,
+            Self::PermissionDenied { .. } => EACCES,
+            Self::OutOfMemory { .. } => ENOMEM,
+            Self::InvalidArgument { .. } => EINVAL,
+            Self::InvalidParameter { errno, .. } | Self::Other { errno, .. } => *errno,
+        }
+    }
+
+    /// Name of the failed call, such as `"_dup2"`.
+    pub fn op(&self) -> &'static str {
+        match self {
+            Self::ProgramNotFound { op, .. }
+            | Self::TooManyOpenFiles { op, .. }
+            | Self::BadDescriptor { op, .. }
+            | Self::ArgumentListTooLong { op, .. }
+            | Self::ExecFormat { op, .. }
+            | Self::PermissionDenied { op, .. }
+            | Self::OutOfMemory { op, .. }
+            | Self::InvalidArgument { op, .. }
+            | Self::InvalidParameter { op, .. }
+            | Self::Other { op, .. } => op,
+        }
+    }
+
+    /// File descriptor involved, if any.
+    pub fn fd(&self) -> Option<c_int> {
+        match self {
+            Self::ProgramNotFound { fd, .. }
+            | Self::TooManyOpenFiles { fd, .. }
+            | Self::BadDescriptor { fd, .. }
+            | Self::ArgumentListTooLong { fd, .. }
+            | Self::ExecFormat { fd, .. }
+            | Self::PermissionDenied { fd, .. }
+            | Self::OutOfMemory { fd, .. }
+            | Self::InvalidArgument { fd, .. }
+            | Self::InvalidParameter { fd, .. }
+            | Self::Other { fd, .. } => *fd,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Self::ProgramNotFound { .. } => "program not found",
+            Self::TooManyOpenFiles { .. } => "too many open files",
+            Self::BadDescriptor { .. } => "bad file descriptor",
+            Self::ArgumentListTooLong { .. } => "argument list too long",
+            Self::ExecFormat { .. } => "exec format error",
+            Self::PermissionDenied { .. } => "permission denied",
+            Self::OutOfMemory { .. } => "out of memory",
+            Self::InvalidArgument { .. } => "invalid argument",
+            Self::InvalidParameter { .. } => "invalid parameter",
+            Self::Other { .. } => "error",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidParameter { op, report, .. } => {
+                write!(f, "invalid parameter in {}", op)?;
+                if *report != InvalidParameterReport::default() {
+                    write!(f, ": {}", report)?;
+                }
+            }
+            Self::Other { op, errno, .. } => write!(f, "{} failed: error (errno {})", op, errno)?,
+            _ => write!(f, "{} failed: {}", self.op(), self.description())?,
+        }
+        if let Some(fd) = self.fd() {
+            write!(f, " (fd {})", fd)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        let kind = match err {
+            Error::ProgramNotFound { .. } => io::ErrorKind::NotFound,
+            Error::PermissionDenied { .. } => io::ErrorKind::PermissionDenied,
+            Error::OutOfMemory { .. } => io::ErrorKind::OutOfMemory,
+            Error::BadDescriptor { .. }
+            | Error::ArgumentListTooLong { .. }
+            | Error::InvalidArgument { .. }
+            | Error::InvalidParameter { .. } => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping() {
+        let table = [
+            (2, Error::ProgramNotFound { op: "x", fd: None }),
+            (24, Error::TooManyOpenFiles { op: "x", fd: None }),
+            (9, Error::BadDescriptor { op: "x", fd: None }),
+            (7, Error::ArgumentListTooLong { op: "x", fd: None }),
+            (8, Error::ExecFormat { op: "x", fd: None }),
+            (13, Error::PermissionDenied { op: "x", fd: None }),
+            (12, Error::OutOfMemory { op: "x", fd: None }),
+            (22, Error::InvalidArgument { op: "x", fd: None }),
+            (
+                28,
+                Error::Other {
+                    op: "x",
+                    fd: None,
+                    errno: 28,
+                },
+            ),
+        ];
+        for (errno, expected) in table {
+            let err = Error::from_errno(errno, "x", None);
+            assert_eq!(expected, err);
+            assert_eq!(errno, err.errno());
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let err = Error::from_errno(9, "_dup2", Some(5));
+        assert_eq!("_dup2 failed: bad file descriptor (fd 5)", err.to_string());
+        assert_eq!(Some(5), err.fd());
+        assert_eq!("_dup2", err.op());
+
+        let err = Error::from_errno(28, "_wspawnvp", None);
+        assert_eq!("_wspawnvp failed: error (errno 28)", err.to_string());
+    }
+
+    #[test]
+    fn test_invalid_parameter() {
+        let err = Error::InvalidParameter {
+            op: "_dup2",
+            fd: Some(5),
+            errno: 9,
+            report: InvalidParameterReport::default(),
+        };
+        assert_eq!("invalid parameter in _dup2 (fd 5)", err.to_string());
+        assert_eq!(9, err.errno());
+        assert_eq!(io::ErrorKind::InvalidInput, io::Error::from(err).kind());
+    }
+
+    #[test]
+    fn test_io_error() {
+        let err = io::Error::from(Error::from_errno(2, "_wspawnvp", None));
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        let inner = err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert_eq!(
+            &Error::ProgramNotFound {
+                op: "_wspawnvp",
+                fd: None
+            },
+            inner
+        );
+    }
+}
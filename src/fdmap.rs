@@ -0,0 +1,275 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::iter::FromIterator;
+use std::marker::PhantomData;
+use std::mem::{self, ManuallyDrop};
+use std::os::raw::c_int;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::plan::{self, Op, Slot};
+use crate::FileDescriptor;
+
+#[cfg(unix)]
+use crate::unix as imp;
+#[cfg(windows)]
+use crate::win as imp;
+
+/// File descriptor layout for a child process.
+///
+/// Maps any number of source [`FileDescriptor`]s to target fd numbers and applies
+/// them in one step, instead of nesting [`move_fd`](crate::move_fd) calls.
+///
+/// # Example
+///
+/// ```rust
+/// # fn main() -> std::io::Result<()> {
+/// use winspawn::{spawn, FdMap, FileDescriptor, Mode};
+///
+/// let file = std::fs::File::open("Cargo.toml")?;
+/// let fd = FileDescriptor::from_raw_handle(file, Mode::ReadOnly)?;
+///
+/// let mut proc = FdMap::new()
+///     .insert(3, &fd)
+///     .insert(4, &fd)
+///     .apply(|| spawn("python", ["-c", "import os; print(os.stat(3), os.stat(4))"]))?;
+/// assert!(proc.wait()?.success());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct FdMap<'a> {
+    // target fd -> source fd
+    fds: BTreeMap<c_int, c_int>,
+    _borrow: PhantomData<&'a ()>,
+}
+
+impl<'a> FdMap<'a> {
+    /// Construct empty mapping.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Map `fd` to `dest`.
+    ///
+    /// A later call with the same `dest` replaces the former.
+    pub fn insert(&mut self, dest: c_int, fd: &'a FileDescriptor) -> &mut Self {
+        self.insert_raw(dest, fd.as_raw_fd());
+        self
+    }
+
+    /// Caller must keep `src` open for `'a`.
+    pub(crate) fn insert_raw(&mut self, dest: c_int, src: c_int) {
+        self.fds.insert(dest, src);
+    }
+
+    /// Mapping as `(dest, source)` pairs in `dest` order.
+    pub fn iter(&self) -> impl Iterator<Item = (c_int, c_int)> + '_ {
+        self.fds.iter().map(|(dest, src)| (*dest, *src))
+    }
+
+    /// Number of mapped descriptors.
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    /// `true` if nothing mapped.
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// Apply mapping temporary and call func.
+    ///
+    /// Every displaced descriptor is restored (or closed if it was not open) after func returns,
+    /// and also when applying fails part way.
+    ///
+    /// This function valid in this library lock acquires.
+    pub fn apply<F, R, E>(&self, func: F) -> Result<R, E>
+    where
+        F: FnOnce() -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        apply_fds(self, func)
+    }
+}
+
+impl<'a> Extend<(c_int, &'a FileDescriptor)> for FdMap<'a> {
+    fn extend<I: IntoIterator<Item = (c_int, &'a FileDescriptor)>>(&mut self, iter: I) {
+        for (dest, fd) in iter {
+            self.insert(dest, fd);
+        }
+    }
+}
+
+impl<'a> FromIterator<(c_int, &'a FileDescriptor)> for FdMap<'a> {
+    fn from_iter<I: IntoIterator<Item = (c_int, &'a FileDescriptor)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        map.extend(iter);
+        map
+    }
+}
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+thread_local!(static ENTERED: Cell<bool> = const { Cell::new(false) });
+
+/// The library lock over the process-wide fd table. Reentrant on the same thread.
+#[derive(Debug)]
+pub(crate) struct StaticMutex(Option<MutexGuard<'static, ()>>);
+
+impl StaticMutex {
+    pub(crate) fn acquire() -> Self {
+        let enter = !ENTERED.with(|b| b.replace(true));
+        if enter {
+            let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            Self(Some(guard))
+        } else {
+            Self(None)
+        }
+    }
+}
+
+impl Drop for StaticMutex {
+    fn drop(&mut self) {
+        if self.0.take().is_some() {
+            ENTERED.with(|b| b.set(false));
+        }
+    }
+}
+
+/// Move fd temporary and call func.
+///
+/// This function valid in this library lock acquires.
+pub fn move_fd<E, R, F>(fd: &FileDescriptor, dest: c_int, func: F) -> Result<R, E>
+where
+    F: FnOnce(&FileDescriptor) -> Result<R, E>,
+    E: From<io::Error>,
+{
+    log::trace!("begin move_fd with {:?} {}.", fd, dest);
+
+    FdMap::new().insert(dest, fd).apply(|| {
+        // restored by FdMap
+        let newfd = ManuallyDrop::new(unsafe { FileDescriptor::from_raw_fd(dest) });
+        func(&newfd)
+    })
+}
+
+#[derive(Debug)]
+enum Displace {
+    /// Overwritten. Restore from backup, or close if was not open.
+    Moved(c_int, Option<FileDescriptor>),
+    /// Made inheritable. Not inherited again.
+    Inherited(c_int),
+}
+
+#[derive(Debug, Default)]
+struct Displaced(Vec<Displace>);
+
+impl Displaced {
+    fn restore(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        while let Some(displace) = self.0.pop() {
+            log::trace!("restore {:?}", displace);
+            let r = match displace {
+                Displace::Moved(dest, Some(backup)) => backup.dup2(dest).map(mem::forget),
+                Displace::Moved(dest, None) => {
+                    drop(unsafe { FileDescriptor::from_raw_fd(dest) });
+                    Ok(())
+                }
+                Displace::Inherited(fd) => imp::set_noinherit(fd),
+            };
+            if let Err(err) = r {
+                log::warn!("failed to restore: {}", err);
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+}
+
+impl Drop for Displaced {
+    fn drop(&mut self) {
+        self.restore().ok();
+    }
+}
+
+/// [`FdMap::apply`] on the parent's own fd table.
+fn apply_fds<F, R, E>(map: &FdMap<'_>, func: F) -> Result<R, E>
+where
+    F: FnOnce() -> Result<R, E>,
+    E: From<io::Error>,
+{
+    log::trace!("begin apply {:?}.", map);
+
+    // lock for modify file descriptor
+    let _lock = StaticMutex::acquire();
+
+    let reserved = map.iter().map(|(dest, _)| dest).collect::<BTreeSet<_>>();
+    let mut displaced = Displaced::default();
+    for (dest, src) in map.iter() {
+        if src != dest {
+            let backup = if imp::is_open(dest) {
+                let original = ManuallyDrop::new(unsafe { FileDescriptor::from_raw_fd(dest) });
+                Some(imp::dup_avoiding(&original, &reserved)?)
+            } else {
+                None
+            };
+            log::trace!("backup {} {:?}.", dest, backup);
+            displaced.0.push(Displace::Moved(dest, backup));
+        }
+    }
+
+    let mut temps = vec![];
+    for op in plan::plan(map.iter()) {
+        log::trace!("{:?}", op);
+        match op {
+            Op::Save { fd, .. } => {
+                let fd = ManuallyDrop::new(unsafe { FileDescriptor::from_raw_fd(fd) });
+                temps.push(Some(imp::dup_avoiding(&fd, &reserved)?));
+            }
+            Op::Dup2 { src, dest } => {
+                let newfd = match src {
+                    Slot::Fd(fd) => {
+                        let fd = ManuallyDrop::new(unsafe { FileDescriptor::from_raw_fd(fd) });
+                        fd.dup2(dest)?
+                    }
+                    Slot::Temp(temp) => temps[temp].as_ref().unwrap().dup2(dest)?,
+                };
+                // owned by displaced
+                mem::forget(newfd);
+            }
+            Op::Inherit(fd) => {
+                if imp::set_inheritable(fd, &reserved)? {
+                    displaced.0.push(Displace::Inherited(fd));
+                }
+            }
+            Op::Close(temp) => drop(temps[temp].take()),
+        }
+    }
+    log::trace!("dup2 ok.");
+
+    let result = func();
+    let restored = displaced.restore();
+    let result = result?;
+    restored?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mutex() {
+        let lock1 = StaticMutex::acquire();
+        let lock2 = StaticMutex::acquire(); // reentrant
+        assert!(lock1.0.is_some());
+        assert!(lock2.0.is_none());
+        drop((lock2, lock1));
+
+        // free again for other threads
+        std::thread::spawn(|| assert!(StaticMutex::acquire().0.is_some()))
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Reports of the CRT invalid parameter handler.
+//!
+//! On an invalid argument (such as a closed fd to `_dup2`) the UCRT calls the invalid
+//! parameter handler, which aborts the process by default. Functions marked with
+//! `#[winspawn_macro::ignore_invalid_handler]` install a handler that stores the
+//! report in a thread-local instead, and a failure is returned as
+//! [`Error::InvalidParameter`](crate::Error::InvalidParameter).
+//!
+//! [`InvalidParameterGuard`] and [`with_invalid_parameter_handler`] give the same
+//! protection to your own CRT calls, such as on an fd from
+//! [`FileDescriptor::into_raw_fd`](crate::FileDescriptor::into_raw_fd). Outside
+//! Windows they do nothing, so portable code compiles unchanged.
+//!
+//! # Example
+//!
+//! ```rust
+//! use winspawn::invalid::{with_invalid_parameter_handler, InvalidParameterGuard};
+//!
+//! let guard = InvalidParameterGuard::new();
+//! // call the CRT here. an invalid fd fails with `EBADF` instead of aborting.
+//! assert_eq!(None, guard.take_report());
+//! drop(guard); // the previous handler is back
+//!
+//! let n = with_invalid_parameter_handler(|| 1 + 1);
+//! assert_eq!(2, n);
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// What the CRT reported to the invalid parameter handler.
+///
+/// The release CRT passes no details, only the fact. The fields are `None` then.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InvalidParameterReport {
+    expression: Option<String>,
+    function: Option<String>,
+    file: Option<String>,
+    line: u32,
+}
+
+impl InvalidParameterReport {
+    /// The failed expression.
+    pub fn expression(&self) -> Option<&str> {
+        self.expression.as_deref()
+    }
+
+    /// The CRT function that detected it.
+    pub fn function(&self) -> Option<&str> {
+        self.function.as_deref()
+    }
+
+    /// The CRT source file.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Line in [`InvalidParameterReport::file`]. 0 if unknown.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+}
+
+impl fmt::Display for InvalidParameterReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(expression) = &self.expression {
+            write!(f, "`{}`", expression)?;
+        }
+        if let Some(function) = &self.function {
+            write!(f, " in {}", function)?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, " at {}:{}", file, self.line)?;
+        }
+        Ok(())
+    }
+}
+
+thread_local!(static REPORT: RefCell<Option<InvalidParameterReport>> = const { RefCell::new(None) });
+// number of live guards on this thread
+thread_local!(static ACTIVE: Cell<usize> = const { Cell::new(0) });
+// handler before the outermost guard
+#[cfg(windows)]
+thread_local!(static OLD: Cell<crate::sys::_invalid_parameter_handler> = const { Cell::new(None) });
+
+/// Forget the report of an earlier call.
+pub(crate) fn clear() {
+    REPORT.with(|r| r.borrow_mut().take());
+}
+
+/// Take the report since the last [`clear`].
+///
+/// `None` unless an [`InvalidParameterGuard`] is live, as only a guard around the
+/// failing call makes the report belong to it.
+pub(crate) fn take() -> Option<InvalidParameterReport> {
+    if ACTIVE.with(Cell::get) == 0 {
+        return None;
+    }
+    REPORT.with(|r| r.borrow_mut().take())
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+fn store(report: InvalidParameterReport) {
+    REPORT.with(|r| *r.borrow_mut() = Some(report));
+}
+
+/// Invalid parameter handler storing the report.
+#[cfg(windows)]
+pub(crate) unsafe extern "C" fn capture(
+    expression: *const crate::sys::wchar_t,
+    function: *const crate::sys::wchar_t,
+    file: *const crate::sys::wchar_t,
+    line: std::os::raw::c_uint,
+    _reserved: usize,
+) {
+    unsafe fn string(s: *const u16) -> Option<String> {
+        if s.is_null() {
+            return None;
+        }
+        let len = (0..).take_while(|i| *s.add(*i) != 0).count();
+        Some(String::from_utf16_lossy(std::slice::from_raw_parts(s, len)))
+    }
+
+    store(InvalidParameterReport {
+        expression: string(expression),
+        function: string(function),
+        file: string(file),
+        line,
+    });
+}
+
+/// Installs the capturing invalid parameter handler on this thread until dropped,
+/// then restores the previous one, also on panic. A report not taken by then is
+/// forgotten.
+///
+/// Guards nest. The outermost one installs the handler, and the handler is restored
+/// when the last live guard of the thread is dropped, so the drop order does not
+/// matter. Nested guards share one report.
+///
+/// Not `Send`: the handler belongs to the thread that created the guard.
+/// A no-op outside Windows.
+#[derive(Debug)]
+pub struct InvalidParameterGuard {
+    // the handler is per thread
+    _not_send: PhantomData<*const ()>,
+}
+
+impl InvalidParameterGuard {
+    /// Install the handler unless a guard is live, forgetting any report of an earlier
+    /// guard.
+    pub fn new() -> Self {
+        let outermost = ACTIVE.with(|active| {
+            active.set(active.get() + 1);
+            active.get() == 1
+        });
+        if outermost {
+            clear();
+            #[cfg(windows)]
+            OLD.with(|old| {
+                old.set(unsafe {
+                    crate::sys::_set_thread_local_invalid_parameter_handler(Some(capture))
+                })
+            });
+        }
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Take the report of the last invalid parameter since the outermost guard was
+    /// created.
+    ///
+    /// Always `None` outside Windows.
+    pub fn take_report(&self) -> Option<InvalidParameterReport> {
+        take()
+    }
+}
+
+impl Default for InvalidParameterGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run `f` with [`InvalidParameterGuard`] installed.
+pub fn with_invalid_parameter_handler<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = InvalidParameterGuard::new();
+    f()
+}
+
+impl Drop for InvalidParameterGuard {
+    fn drop(&mut self) {
+        let last = ACTIVE.with(|active| {
+            active.set(active.get() - 1);
+            active.get() == 0
+        });
+        if last {
+            #[cfg(windows)]
+            OLD.with(|old| unsafe {
+                crate::sys::_set_thread_local_invalid_parameter_handler(old.take());
+            });
+            // not to be taken for a later failure outside any guard
+            clear();
+        }
+    }
+}
+
+/// Future with [`InvalidParameterGuard`] around each poll.
+#[doc(hidden)]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Guarded<F>(F);
+
+impl<F> Guarded<F> {
+    pub fn new(fut: F) -> Self {
+        Self(fut)
+    }
+}
+
+impl<F: Future> Future for Guarded<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _guard = InvalidParameterGuard::new();
+        // structural pinning of the only field
+        unsafe { self.map_unchecked_mut(|this| &mut this.0) }.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let _guard = InvalidParameterGuard::new();
+        assert_eq!(None, take());
+
+        let report = InvalidParameterReport {
+            expression: Some("(fh >= 0 && (unsigned)fh < (unsigned)_nhandle)".into()),
+            function: Some("_dup2".into()),
+            file: Some("dup2.cpp".into()),
+            line: 38,
+        };
+        store(report.clone());
+        // only on this thread
+        std::thread::spawn(|| assert_eq!(None, InvalidParameterGuard::new().take_report()))
+            .join()
+            .unwrap();
+        assert_eq!(Some(report.clone()), take());
+        assert_eq!(None, take());
+
+        assert_eq!(
+            "`(fh >= 0 && (unsigned)fh < (unsigned)_nhandle)` in _dup2 at dup2.cpp:38",
+            report.to_string()
+        );
+        assert_eq!("", InvalidParameterReport::default().to_string());
+    }
+
+    #[test]
+    fn test_guard() {
+        store(InvalidParameterReport::default());
+        let guard = InvalidParameterGuard::new();
+        // an earlier report is forgotten
+        assert_eq!(None, guard.take_report());
+        store(InvalidParameterReport::default());
+        assert!(guard.take_report().is_some());
+
+        // a report left by a guarded call is not taken after the guard
+        store(InvalidParameterReport::default());
+        drop(guard);
+        assert_eq!(None, take());
+        store(InvalidParameterReport::default());
+        assert_eq!(None, take());
+        assert_eq!(None, InvalidParameterGuard::new().take_report());
+
+        let r = std::panic::catch_unwind(|| with_invalid_parameter_handler(|| panic!("x")));
+        assert!(r.is_err());
+        assert_eq!(3, with_invalid_parameter_handler(|| 3));
+    }
+
+    #[test]
+    fn test_nested() {
+        let outer = InvalidParameterGuard::new();
+        store(InvalidParameterReport::default());
+        // the outer report is kept
+        let inner = InvalidParameterGuard::new();
+        drop(inner);
+        assert!(outer.take_report().is_some());
+
+        // dropped out of order
+        let inner = InvalidParameterGuard::new();
+        store(InvalidParameterReport::default());
+        drop(outer);
+        assert!(inner.take_report().is_some());
+        drop(inner);
+        assert_eq!(0, ACTIVE.with(Cell::get));
+        store(InvalidParameterReport::default());
+        assert_eq!(None, take());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_guard_restore() {
+        use crate::sys::_get_thread_local_invalid_parameter_handler;
+
+        let before = unsafe { _get_thread_local_invalid_parameter_handler() };
+        let result = std::panic::catch_unwind(|| {
+            let _guard = InvalidParameterGuard::new();
+            assert!(unsafe { _get_thread_local_invalid_parameter_handler() }.is_some());
+            panic!("restored on unwind");
+        });
+        assert!(result.is_err());
+        assert_eq!(
+            before.map(|h| h as usize),
+            unsafe { _get_thread_local_invalid_parameter_handler() }.map(|h| h as usize)
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Plan for moving file descriptors into place.
+//!
+//! Applying `dest <- src` mappings one by one clobbers a source when it is also
+//! the target of another mapping (e.g. swap 3 and 4). [`plan`] orders the moves
+//! so every source is read before it is overwritten, and saves a descriptor to
+//! a temporary only to break a cycle.
+
+use std::collections::BTreeMap;
+use std::os::raw::c_int;
+
+/// Where a source descriptor is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Slot {
+    /// File descriptor.
+    Fd(c_int),
+    /// Temporary saved by [`Op::Save`]. Real fd number is chosen by the executor.
+    Temp(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    /// Duplicate `fd` to a new temporary. (`dup`)
+    ///
+    /// Temporaries are numbered from 0 in order of appearance.
+    Save { fd: c_int, temp: usize },
+    /// Duplicate `src` onto `dest`, replacing it. (`dup2`)
+    Dup2 { src: Slot, dest: c_int },
+    /// `fd` is already in place. Only needs to be inheritable.
+    Inherit(c_int),
+    /// Close temporary.
+    Close(usize),
+}
+
+/// Plan `(dest, src)` mappings.
+///
+/// If the same `dest` appears twice, the later wins.
+pub(crate) fn plan<I>(fds: I) -> Vec<Op>
+where
+    I: IntoIterator<Item = (c_int, c_int)>,
+{
+    let mut ops = vec![];
+    let mut pending = BTreeMap::new();
+    for (dest, src) in fds.into_iter().collect::<BTreeMap<_, _>>() {
+        if dest == src {
+            ops.push(Op::Inherit(dest));
+        } else {
+            pending.insert(dest, Slot::Fd(src));
+        }
+    }
+
+    let mut ntemps = 0;
+    while !pending.is_empty() {
+        // a target is ready to overwrite when no pending move still reads it
+        let ready = pending
+            .keys()
+            .find(|dest| !pending.values().any(|src| *src == Slot::Fd(**dest)))
+            .copied();
+
+        if let Some(dest) = ready {
+            let src = pending.remove(&dest).unwrap();
+            ops.push(Op::Dup2 { src, dest });
+            continue;
+        }
+
+        // only cycles remain. save one of them and read it from the temporary instead.
+        let fd = *pending.keys().next().unwrap();
+        let temp = ntemps;
+        ntemps += 1;
+        ops.push(Op::Save { fd, temp });
+        for src in pending.values_mut() {
+            if *src == Slot::Fd(fd) {
+                *src = Slot::Temp(temp);
+            }
+        }
+    }
+
+    ops.extend((0..ntemps).map(Op::Close));
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeSet;
+
+    use proptest::prelude::*;
+
+    /// Run plan against fd table that every fd refers to file of the same number.
+    fn run(ops: &[Op], open: &BTreeSet<c_int>) -> BTreeMap<c_int, c_int> {
+        let mut table = open.iter().map(|fd| (*fd, *fd)).collect::<BTreeMap<_, _>>();
+        let mut temps = vec![];
+
+        for op in ops {
+            match *op {
+                Op::Save { fd, temp } => {
+                    assert_eq!(temps.len(), temp);
+                    temps.push(Some(table[&fd]));
+                }
+                Op::Dup2 { src, dest } => {
+                    let file = match src {
+                        Slot::Fd(fd) => table[&fd],
+                        Slot::Temp(temp) => temps[temp].unwrap(),
+                    };
+                    table.insert(dest, file);
+                }
+                Op::Inherit(fd) => assert!(table.contains_key(&fd)),
+                Op::Close(temp) => {
+                    temps[temp].take().unwrap();
+                }
+            }
+        }
+
+        assert!(temps.iter().all(Option::is_none), "temporary leaked");
+        table
+    }
+
+    fn check(fds: &BTreeMap<c_int, c_int>) -> Vec<Op> {
+        let ops = plan(fds.iter().map(|(dest, src)| (*dest, *src)));
+        let open = fds.values().copied().collect::<BTreeSet<_>>();
+        let table = run(&ops, &open);
+
+        for (dest, src) in fds {
+            assert_eq!(table[dest], *src, "{} <- {} by {:?}", dest, src, ops);
+        }
+        for (fd, file) in &table {
+            if !fds.contains_key(fd) {
+                assert_eq!(fd, file, "{} clobbered by {:?}", fd, ops);
+            }
+        }
+        ops
+    }
+
+    fn ntemps(ops: &[Op]) -> usize {
+        ops.iter()
+            .filter(|op| matches!(op, Op::Save { .. }))
+            .count()
+    }
+
+    #[test]
+    fn test_chain() {
+        let ops = check(&[(3, 4), (4, 5), (5, 6)].iter().copied().collect());
+        assert_eq!(0, ntemps(&ops));
+    }
+
+    #[test]
+    fn test_swap() {
+        let ops = check(&[(3, 4), (4, 3)].iter().copied().collect());
+        assert_eq!(1, ntemps(&ops));
+    }
+
+    #[test]
+    fn test_rotate() {
+        let ops = check(&[(4, 3), (5, 4), (3, 5)].iter().copied().collect());
+        assert_eq!(1, ntemps(&ops));
+    }
+
+    #[test]
+    fn test_identity() {
+        let ops = check(&[(3, 3), (4, 3)].iter().copied().collect());
+        assert_eq!(
+            vec![
+                Op::Inherit(3),
+                Op::Dup2 {
+                    src: Slot::Fd(3),
+                    dest: 4
+                }
+            ],
+            ops
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_any_mapping(fds in prop::collection::btree_map(0..8, 0..8, 0..8)) {
+            let ops = check(&fds);
+            // at most one temporary per cycle
+            prop_assert!(ntemps(&ops) <= fds.len() / 2);
+        }
+    }
+}
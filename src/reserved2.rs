@@ -0,0 +1,292 @@
+//! CRT fd-inheritance block.
+//!
+//! The Universal CRT passes its fd table to a child process through
+//! `STARTUPINFO.cbReserved2` / `lpReserved2`. The child CRT reads it on startup.
+//!
+//! Layout (unaligned, little endian):
+//!
+//! | size                      | content                      |
+//! |---------------------------|------------------------------|
+//! | `int`                     | number of fds `n`            |
+//! | `n` x `unsigned char`     | flags of fd `0..n`           |
+//! | `n` x `intptr_t`          | `HANDLE` of fd `0..n`        |
+//!
+//! An unused fd is encoded as flags `0` and `INVALID_HANDLE_VALUE`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use winspawn::reserved2::{decode, encode, Entry, Flags};
+//!
+//! let entries = vec![Entry {
+//!     fd: 3,
+//!     flags: Flags::FOPEN | Flags::FPIPE,
+//!     handle: 0x1234,
+//! }];
+//! let block = encode(&entries).unwrap();
+//! assert_eq!(4 + 4 * (1 + std::mem::size_of::<isize>()), block.len());
+//! assert_eq!(entries, decode(&block).unwrap());
+//! ```
+
+use std::convert::TryFrom;
+use std::io;
+use std::mem;
+use std::ops::{BitAnd, BitOr, BitOrAssign};
+use std::os::raw::c_int;
+
+/// `INVALID_HANDLE_VALUE`
+pub const INVALID_HANDLE: isize = -1;
+
+const COUNT_SIZE: usize = mem::size_of::<c_int>();
+const HANDLE_SIZE: usize = mem::size_of::<isize>();
+
+/// Per fd flags. (`_osfile` of CRT)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Flags(u8);
+
+impl Flags {
+    /// Open.
+    pub const FOPEN: Self = Self(0x01);
+    /// End of file reached.
+    pub const FEOFLAG: Self = Self(0x02);
+    /// CR-LF across read buffer.
+    pub const FCRLF: Self = Self(0x04);
+    /// Pipe.
+    pub const FPIPE: Self = Self(0x08);
+    /// Not inherited by child process.
+    pub const FNOINHERIT: Self = Self(0x10);
+    /// Append mode.
+    pub const FAPPEND: Self = Self(0x20);
+    /// Device. (console etc)
+    pub const FDEV: Self = Self(0x40);
+    /// Text mode.
+    pub const FTEXT: Self = Self(0x80);
+
+    /// Construct from raw bits.
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// Raw bits.
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// `true` if all of `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Flags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for Flags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// One fd of the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entry {
+    /// File descriptor in the child process.
+    pub fd: c_int,
+    /// Flags.
+    pub flags: Flags,
+    /// Windows `HANDLE` value. Must be inheritable.
+    pub handle: isize,
+}
+
+impl Entry {
+    fn is_unused(&self) -> bool {
+        self.flags == Flags::default() && self.handle == INVALID_HANDLE
+    }
+}
+
+/// Build the block from entries.
+///
+/// Entries can be any order. The block covers fds up to the largest one.
+pub fn encode(entries: &[Entry]) -> io::Result<Vec<u8>> {
+    let count = match entries.iter().map(|e| e.fd).max() {
+        Some(max) if max < 0 => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("negative fd: {}", max),
+            ))
+        }
+        Some(max) => max as usize + 1,
+        None => 0,
+    };
+
+    let len = COUNT_SIZE + count * (1 + HANDLE_SIZE);
+    if len > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("too many fds: {}", count),
+        ));
+    }
+
+    let mut flags = vec![0u8; count];
+    let mut handles = vec![INVALID_HANDLE; count];
+    let mut used = vec![false; count];
+    for entry in entries {
+        if entry.fd < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("negative fd: {}", entry.fd),
+            ));
+        }
+        let fd = entry.fd as usize;
+        if mem::replace(&mut used[fd], true) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("duplicate fd: {}", entry.fd),
+            ));
+        }
+        flags[fd] = entry.flags.bits();
+        handles[fd] = entry.handle;
+    }
+
+    let mut block = Vec::with_capacity(len);
+    block.extend_from_slice(&(count as c_int).to_le_bytes());
+    block.extend_from_slice(&flags);
+    for handle in handles {
+        block.extend_from_slice(&handle.to_le_bytes());
+    }
+    Ok(block)
+}
+
+/// Parse the block.
+///
+/// Unused fds are omitted. Bytes after the last handle are ignored as the CRT does.
+pub fn decode(block: &[u8]) -> io::Result<Vec<Entry>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    if block.len() < COUNT_SIZE {
+        return Err(invalid("block too short"));
+    }
+    let (count, rest) = block.split_at(COUNT_SIZE);
+    let count = c_int::from_le_bytes(<[u8; COUNT_SIZE]>::try_from(count).unwrap());
+    let count = usize::try_from(count).map_err(|_| invalid("negative count"))?;
+
+    let needs = count
+        .checked_mul(1 + HANDLE_SIZE)
+        .ok_or_else(|| invalid("count too large"))?;
+    if rest.len() < needs {
+        return Err(invalid("block too short"));
+    }
+    let (flags, handles) = rest.split_at(count);
+
+    let entries = flags
+        .iter()
+        .zip(handles.chunks_exact(HANDLE_SIZE))
+        .enumerate()
+        .map(|(fd, (flags, handle))| Entry {
+            fd: fd as c_int,
+            flags: Flags(*flags),
+            handle: isize::from_le_bytes(<[u8; HANDLE_SIZE]>::try_from(handle).unwrap()),
+        })
+        .filter(|entry| !entry.is_unused())
+        .collect();
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_layout() {
+        let block = encode(&[
+            Entry {
+                fd: 2,
+                flags: Flags::FOPEN | Flags::FDEV | Flags::FTEXT,
+                handle: 0x10,
+            },
+            Entry {
+                fd: 0,
+                flags: Flags::FOPEN | Flags::FPIPE,
+                handle: 0x20,
+            },
+        ])
+        .unwrap();
+
+        let mut expected = vec![3, 0, 0, 0, 0x09, 0, 0xc1];
+        expected.extend_from_slice(&0x20isize.to_le_bytes());
+        expected.extend_from_slice(&(-1isize).to_le_bytes());
+        expected.extend_from_slice(&0x10isize.to_le_bytes());
+        assert_eq!(expected, block);
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(vec![0, 0, 0, 0], encode(&[]).unwrap());
+        assert_eq!(Vec::<Entry>::new(), decode(&[0, 0, 0, 0]).unwrap());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let entry = Entry {
+            fd: 0,
+            flags: Flags::FOPEN,
+            handle: 1,
+        };
+        assert!(encode(&[entry, entry]).is_err());
+        assert!(encode(&[Entry { fd: -1, ..entry }]).is_err());
+        assert!(encode(&[Entry {
+            fd: 0x2000,
+            ..entry
+        }])
+        .is_err());
+
+        assert!(decode(&[1, 0]).is_err());
+        assert!(decode(&[1, 0, 0, 0, 1]).is_err());
+        assert!(decode(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    fn entry() -> impl Strategy<Value = Entry> {
+        (0..64, any::<u8>(), any::<isize>())
+            .prop_map(|(fd, flags, handle)| Entry {
+                fd,
+                flags: Flags(flags),
+                handle,
+            })
+            .prop_filter("unused", |e| !e.is_unused())
+    }
+
+    proptest! {
+        #[test]
+        fn test_roundtrip(entries in prop::collection::vec(entry(), 0..16)) {
+            let mut entries = entries;
+            entries.sort_by_key(|e| e.fd);
+            entries.dedup_by_key(|e| e.fd);
+
+            let block = encode(&entries).unwrap();
+            prop_assert_eq!(entries, decode(&block).unwrap());
+        }
+
+        #[test]
+        fn test_decode_any(block in prop::collection::vec(any::<u8>(), 0..128)) {
+            if let Ok(entries) = decode(&block) {
+                let block = encode(&entries).unwrap();
+                prop_assert_eq!(entries, decode(&block).unwrap());
+            }
+        }
+    }
+}
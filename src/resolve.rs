@@ -0,0 +1,174 @@
+//! Program lookup of `_wspawnvp`.
+//!
+//! The CRT finds the program to run in this order:
+//!
+//! 1. The name as given, relative to the current directory.
+//! 2. If the name contains no `/`, `\` or `:`, each directory of `PATH` in order.
+//!
+//! In each place the name is tried as is, then with `.com`, `.exe`, `.bat` and `.cmd`
+//! appended, and the first existing file wins. So `python.bat` in the current
+//! directory shadows `python.exe` in `PATH`.
+//!
+//! [`resolve_program`] follows the same order with Windows rules on every platform,
+//! so it can be tested anywhere. [`Command::resolve`](crate::Command::resolve) spawns
+//! the resolved path instead of letting the CRT search.
+//!
+//! # Example
+//!
+//! ```rust
+//! use winspawn::resolve::resolve_program;
+//!
+//! let dir = std::env::current_dir().unwrap();
+//! let found = resolve_program("Cargo", None, Some(".toml".as_ref()), &dir).unwrap();
+//! assert_eq!(dir.join("Cargo.toml"), found);
+//! ```
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Extensions the CRT tries, in order.
+pub const DEFAULT_EXTENSIONS: &str = ".com;.exe;.bat;.cmd";
+
+/// `true` if `name` has a directory or drive part.
+fn has_dir(name: &str) -> bool {
+    name.contains(['/', '\\', ':'])
+}
+
+/// `name` in `dir`, as is then with each of `extensions`.
+fn find_in(dir: &Path, name: &str, extensions: &[String]) -> Option<PathBuf> {
+    let path = dir.join(name);
+    if path.is_file() {
+        return Some(path);
+    }
+    extensions.iter().find_map(|ext| {
+        let mut path = path.clone().into_os_string();
+        path.push(ext);
+        let path = PathBuf::from(path);
+        path.is_file().then_some(path)
+    })
+}
+
+/// Find the program `_wspawnvp` would run for `name`.
+///
+/// - `path`: `;` separated directories to search. `None` for no search.
+/// - `pathext`: `;` separated extensions to try. `None` for [`DEFAULT_EXTENSIONS`].
+/// - `cwd`: the directory relative names and `PATH` entries are resolved against.
+///
+/// Fails with [`io::ErrorKind::NotFound`] if nothing matches.
+pub fn resolve_program<N>(
+    name: N,
+    path: Option<&OsStr>,
+    pathext: Option<&OsStr>,
+    cwd: &Path,
+) -> io::Result<PathBuf>
+where
+    N: AsRef<OsStr>,
+{
+    let name = name.as_ref().to_string_lossy();
+    let extensions = pathext
+        .map(OsStr::to_string_lossy)
+        .unwrap_or(DEFAULT_EXTENSIONS.into())
+        .split(';')
+        .filter(|ext| !ext.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+
+    if let Some(found) = find_in(cwd, &name, &extensions) {
+        return Ok(found);
+    }
+
+    if !has_dir(&name) {
+        let path = path.map(OsStr::to_string_lossy).unwrap_or_default();
+        let found = path
+            .split(';')
+            // `"C:\Program Files"` is accepted quoted
+            .map(|dir| dir.trim_matches('"'))
+            .filter(|dir| !dir.is_empty())
+            .find_map(|dir| find_in(&cwd.join(dir), &name, &extensions));
+        if let Some(found) = found {
+            return Ok(found);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("program not found: {}", name),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn touch(dir: &Path, name: &str) {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    fn resolve(name: &str, path: &str, cwd: &Path) -> Option<PathBuf> {
+        resolve_program(name, Some(path.as_ref()), None, cwd).ok()
+    }
+
+    #[test]
+    fn test_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        touch(root, "cwd/python.bat");
+        touch(root, "a/python.exe");
+        touch(root, "a/tool.cmd");
+        touch(root, "a/tool.com");
+        touch(root, "b/tool");
+        touch(root, "b/other.exe");
+        let path = "../a;;\"../b\"";
+        let cwd = root.join("cwd");
+
+        // the current directory first
+        assert_eq!(Some(cwd.join("python.bat")), resolve("python", path, &cwd));
+        assert_eq!(
+            Some(root.join("cwd/../a/python.exe")),
+            resolve("python.exe", path, &cwd)
+        );
+        // as is, then in order of extensions, then the next directory
+        assert_eq!(
+            Some(root.join("cwd/../a/tool.com")),
+            resolve("tool", path, &cwd)
+        );
+        assert_eq!(
+            Some(cwd.join("../b/tool")),
+            resolve("tool", "../b;../a", &cwd)
+        );
+        assert_eq!(
+            Some(cwd.join("../b/other.exe")),
+            resolve("other", path, &cwd)
+        );
+    }
+
+    #[test]
+    fn test_not_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        touch(root, "a/tool.exe");
+        touch(root, "a/run.ps1");
+
+        let err = resolve_program("nothing", Some("a".as_ref()), None, root).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        // a directory part disables the search
+        assert_eq!(None, resolve("./tool", "a", root));
+        assert_eq!(
+            Some(root.join("./a/tool.exe")),
+            resolve("./a/tool", "", root)
+        );
+        // only listed extensions
+        assert_eq!(None, resolve("run", "a", root));
+        assert_eq!(
+            Some(root.join("a/run.ps1")),
+            resolve_program("run", Some("a".as_ref()), Some(".PS1;.ps1".as_ref()), root).ok()
+        );
+        // no search without PATH
+        assert!(resolve_program("tool", None, None, root).is_err());
+    }
+}
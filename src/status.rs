@@ -0,0 +1,389 @@
+use std::fmt;
+use std::os::raw::c_int;
+
+/// Well-known `NTSTATUS` a Windows process exits with when it crashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum NtStatus {
+    /// `STATUS_ACCESS_VIOLATION`
+    AccessViolation,
+    /// `STATUS_IN_PAGE_ERROR`
+    InPageError,
+    /// `STATUS_INVALID_PARAMETER`
+    InvalidParameter,
+    /// `STATUS_ILLEGAL_INSTRUCTION`
+    IllegalInstruction,
+    /// `STATUS_PRIVILEGED_INSTRUCTION`
+    PrivilegedInstruction,
+    /// `STATUS_INTEGER_DIVIDE_BY_ZERO`
+    IntegerDivideByZero,
+    /// `STATUS_INTEGER_OVERFLOW`
+    IntegerOverflow,
+    /// `STATUS_STACK_OVERFLOW`
+    StackOverflow,
+    /// `STATUS_DLL_NOT_FOUND`
+    DllNotFound,
+    /// `STATUS_ENTRYPOINT_NOT_FOUND`
+    EntrypointNotFound,
+    /// `STATUS_CONTROL_C_EXIT`
+    ControlCExit,
+    /// `STATUS_DLL_INIT_FAILED`
+    DllInitFailed,
+    /// `STATUS_HEAP_CORRUPTION`
+    HeapCorruption,
+    /// `STATUS_STACK_BUFFER_OVERRUN` (also `__fastfail`)
+    StackBufferOverrun,
+}
+
+const NTSTATUS: &[(NtStatus, u32, &str, &str)] = &[
+    (
+        NtStatus::AccessViolation,
+        0xC000_0005,
+        "STATUS_ACCESS_VIOLATION",
+        "access violation",
+    ),
+    (
+        NtStatus::InPageError,
+        0xC000_0006,
+        "STATUS_IN_PAGE_ERROR",
+        "in-page I/O error",
+    ),
+    (
+        NtStatus::InvalidParameter,
+        0xC000_000D,
+        "STATUS_INVALID_PARAMETER",
+        "invalid parameter",
+    ),
+    (
+        NtStatus::IllegalInstruction,
+        0xC000_001D,
+        "STATUS_ILLEGAL_INSTRUCTION",
+        "illegal instruction",
+    ),
+    (
+        NtStatus::PrivilegedInstruction,
+        0xC000_0096,
+        "STATUS_PRIVILEGED_INSTRUCTION",
+        "privileged instruction",
+    ),
+    (
+        NtStatus::IntegerDivideByZero,
+        0xC000_0094,
+        "STATUS_INTEGER_DIVIDE_BY_ZERO",
+        "integer divide by zero",
+    ),
+    (
+        NtStatus::IntegerOverflow,
+        0xC000_0095,
+        "STATUS_INTEGER_OVERFLOW",
+        "integer overflow",
+    ),
+    (
+        NtStatus::StackOverflow,
+        0xC000_00FD,
+        "STATUS_STACK_OVERFLOW",
+        "stack overflow",
+    ),
+    (
+        NtStatus::DllNotFound,
+        0xC000_0135,
+        "STATUS_DLL_NOT_FOUND",
+        "dll not found",
+    ),
+    (
+        NtStatus::EntrypointNotFound,
+        0xC000_0139,
+        "STATUS_ENTRYPOINT_NOT_FOUND",
+        "entry point not found",
+    ),
+    (
+        NtStatus::ControlCExit,
+        0xC000_013A,
+        "STATUS_CONTROL_C_EXIT",
+        "terminated by Ctrl+C",
+    ),
+    (
+        NtStatus::DllInitFailed,
+        0xC000_0142,
+        "STATUS_DLL_INIT_FAILED",
+        "dll initialization failed",
+    ),
+    (
+        NtStatus::HeapCorruption,
+        0xC000_0374,
+        "STATUS_HEAP_CORRUPTION",
+        "heap corruption",
+    ),
+    (
+        NtStatus::StackBufferOverrun,
+        0xC000_0409,
+        "STATUS_STACK_BUFFER_OVERRUN",
+        "stack buffer overrun",
+    ),
+];
+
+impl NtStatus {
+    /// Recognise a process exit code.
+    pub fn from_code(code: u32) -> Option<Self> {
+        NTSTATUS
+            .iter()
+            .find(|(_, c, _, _)| *c == code)
+            .map(|(status, _, _, _)| *status)
+    }
+
+    /// Raw `NTSTATUS` value.
+    pub fn code(self) -> u32 {
+        self.entry().1
+    }
+
+    /// Constant name. (e.g. `STATUS_ACCESS_VIOLATION`)
+    pub fn name(self) -> &'static str {
+        self.entry().2
+    }
+
+    fn entry(self) -> &'static (NtStatus, u32, &'static str, &'static str) {
+        NTSTATUS.iter().find(|(s, _, _, _)| *s == self).unwrap()
+    }
+}
+
+impl fmt::Display for NtStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, _, name, desc) = self.entry();
+        write!(f, "{} ({})", desc, name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Repr {
+    /// Exited normally. Full 32 bit code on Windows.
+    Code(u32),
+    /// Terminated by signal. (Unix)
+    #[cfg_attr(windows, allow(dead_code))]
+    Signal { signal: c_int, core_dumped: bool },
+    /// Terminated by [`Child::kill`](crate::Child::kill) with the exit code. (Windows)
+    #[cfg_attr(not(windows), allow(dead_code))]
+    Killed(u32),
+}
+
+/// Exit status of a child process.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io;
+///
+/// fn main() -> io::Result<()> {
+///     let status = winspawn::spawn("cargo", ["--version"])?.wait()?;
+///     assert!(status.success());
+///     assert_eq!(Some(0), status.code());
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExitStatus(Repr);
+
+impl ExitStatus {
+    /// From `GetExitCodeProcess`.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn from_code(code: u32) -> Self {
+        Self(Repr::Code(code))
+    }
+
+    /// Of a process ended by `TerminateProcess` in [`Child::kill`](crate::Child::kill).
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub(crate) fn from_kill(code: u32) -> Self {
+        Self(Repr::Killed(code))
+    }
+
+    /// From `waitpid` status.
+    #[cfg(unix)]
+    pub(crate) fn from_wait_status(status: c_int) -> Self {
+        if libc::WIFSIGNALED(status) {
+            Self(Repr::Signal {
+                signal: libc::WTERMSIG(status),
+                core_dumped: libc::WCOREDUMP(status),
+            })
+        } else {
+            Self(Repr::Code(libc::WEXITSTATUS(status) as u32))
+        }
+    }
+
+    /// `true` if exited with code 0.
+    pub fn success(&self) -> bool {
+        self.0 == Repr::Code(0)
+    }
+
+    /// Exit code. `None` if terminated by signal or killed.
+    ///
+    /// On Windows an `NTSTATUS` code like `0xC0000005` is negative.
+    pub fn code(&self) -> Option<i32> {
+        match self.0 {
+            Repr::Code(code) => Some(code as i32),
+            Repr::Signal { .. } | Repr::Killed(..) => None,
+        }
+    }
+
+    /// `true` if forcibly terminated: by `SIGKILL` on Unix, by
+    /// [`Child::kill`](crate::Child::kill) of this process on Windows.
+    pub fn killed(&self) -> bool {
+        match self.0 {
+            Repr::Killed(..) => true,
+            #[cfg(unix)]
+            Repr::Signal { signal, .. } => signal == libc::SIGKILL,
+            _ => false,
+        }
+    }
+
+    /// Crash reason if the exit code is a well-known `NTSTATUS`.
+    ///
+    /// Only meaningful on Windows. A process ended by [`Child::kill`](crate::Child::kill)
+    /// is not a crash, see [`ExitStatus::killed`].
+    pub fn ntstatus(&self) -> Option<NtStatus> {
+        match self.0 {
+            Repr::Code(code) => NtStatus::from_code(code),
+            Repr::Signal { .. } | Repr::Killed(..) => None,
+        }
+    }
+
+    /// Signal that terminated the process. Always `None` on Windows.
+    pub fn signal(&self) -> Option<c_int> {
+        match self.0 {
+            Repr::Signal { signal, .. } => Some(signal),
+            Repr::Code(..) | Repr::Killed(..) => None,
+        }
+    }
+
+    /// `true` if terminated by signal and dumped core.
+    pub fn core_dumped(&self) -> bool {
+        matches!(
+            self.0,
+            Repr::Signal {
+                core_dumped: true,
+                ..
+            }
+        )
+    }
+}
+
+#[cfg(unix)]
+fn signal_name(signal: c_int) -> Option<&'static str> {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(not(unix))]
+fn signal_name(_: c_int) -> Option<&'static str> {
+    None
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Repr::Code(code) => match NtStatus::from_code(code) {
+                Some(status) => write!(f, "exit code: {:#X}, {}", code, status),
+                None => write!(f, "exit code: {}", code),
+            },
+            Repr::Signal {
+                signal,
+                core_dumped,
+            } => {
+                write!(f, "signal: {}", signal)?;
+                if let Some(name) = signal_name(signal) {
+                    write!(f, " ({})", name)?;
+                }
+                if core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+                Ok(())
+            }
+            Repr::Killed(code) => write!(f, "killed (exit code: {})", code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code() {
+        let status = ExitStatus::from_code(0);
+        assert!(status.success());
+        assert_eq!(Some(0), status.code());
+        assert_eq!("exit code: 0", status.to_string());
+
+        let status = ExitStatus::from_code(1);
+        assert!(!status.success());
+        assert_eq!(None, status.ntstatus());
+        assert_eq!(None, status.signal());
+        assert!(!status.killed());
+    }
+
+    #[test]
+    fn test_killed() {
+        let status = ExitStatus::from_kill(1);
+        assert!(!status.success());
+        assert!(status.killed());
+        assert_eq!(None, status.code());
+        assert_eq!(None, status.ntstatus());
+        assert_eq!(None, status.signal());
+        assert_eq!("killed (exit code: 1)", status.to_string());
+    }
+
+    #[test]
+    fn test_ntstatus() {
+        let status = ExitStatus::from_code(0xC000_0005);
+        assert!(!status.success());
+        assert_eq!(Some(-1073741819), status.code());
+        assert_eq!(Some(NtStatus::AccessViolation), status.ntstatus());
+        assert_eq!(
+            "exit code: 0xC0000005, access violation (STATUS_ACCESS_VIOLATION)",
+            status.to_string()
+        );
+
+        for (ntstatus, code, ..) in NTSTATUS {
+            assert_eq!(Some(*ntstatus), NtStatus::from_code(*code));
+            assert_eq!(*code, ntstatus.code());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signal() {
+        // exited 3
+        let status = ExitStatus::from_wait_status(3 << 8);
+        assert_eq!(Some(3), status.code());
+        assert_eq!(None, status.signal());
+
+        // killed
+        let status = ExitStatus::from_wait_status(libc::SIGKILL);
+        assert!(!status.success());
+        assert_eq!(None, status.code());
+        assert_eq!(Some(libc::SIGKILL), status.signal());
+        assert!(status.killed());
+        assert!(!status.core_dumped());
+        assert_eq!("signal: 9 (SIGKILL)", status.to_string());
+
+        let status = ExitStatus::from_wait_status(libc::SIGSEGV | 0x80);
+        assert!(status.core_dumped());
+        assert!(!status.killed());
+        assert_eq!("signal: 11 (SIGSEGV) (core dumped)", status.to_string());
+    }
+}
@@ -0,0 +1,242 @@
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::raw::c_int;
+use std::thread;
+
+use crate::{pipe, Child, ExitStatus, FdMap, FileDescriptor, Mode, PipeMode};
+
+#[cfg(windows)]
+const NULL_DEVICE: &str = "NUL";
+#[cfg(unix)]
+const NULL_DEVICE: &str = "/dev/null";
+
+/// What fd 0, 1 or 2 of a child process is connected to.
+///
+/// Set by [`Command::stdin`](crate::Command::stdin),
+/// [`Command::stdout`](crate::Command::stdout) and
+/// [`Command::stderr`](crate::Command::stderr).
+#[derive(Debug)]
+pub struct Stdio(Repr);
+
+#[derive(Debug)]
+enum Repr {
+    Inherit,
+    Null,
+    Piped,
+    Fd(FileDescriptor),
+}
+
+impl Stdio {
+    /// The parent's own fd. (default)
+    pub fn inherit() -> Self {
+        Self(Repr::Inherit)
+    }
+
+    /// The null device.
+    pub fn null() -> Self {
+        Self(Repr::Null)
+    }
+
+    /// A new pipe. The parent's end is on [`Child`](crate::Child).
+    pub fn piped() -> Self {
+        Self(Repr::Piped)
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::inherit()
+    }
+}
+
+impl From<FileDescriptor> for Stdio {
+    fn from(fd: FileDescriptor) -> Self {
+        Self(Repr::Fd(fd))
+    }
+}
+
+/// fd of the child, owned or borrowed from [`Stdio`].
+#[derive(Debug)]
+enum ChildEnd<'a> {
+    Owned(FileDescriptor),
+    Borrowed(&'a FileDescriptor),
+}
+
+impl ChildEnd<'_> {
+    fn as_raw_fd(&self) -> c_int {
+        match self {
+            Self::Owned(fd) => fd.as_raw_fd(),
+            Self::Borrowed(fd) => fd.as_raw_fd(),
+        }
+    }
+}
+
+/// fds for a spawn, opened from `[stdin, stdout, stderr]`.
+#[derive(Debug, Default)]
+pub(crate) struct Prepared<'a> {
+    child: [Option<ChildEnd<'a>>; 3],
+    /// Parent ends of [`Stdio::piped`].
+    pub(crate) parent: [Option<FileDescriptor>; 3],
+}
+
+impl<'a> Prepared<'a> {
+    pub(crate) fn new(stdio: [&'a Stdio; 3]) -> io::Result<Self> {
+        let mut prepared = Self::default();
+        for (fd, stdio) in stdio.iter().enumerate() {
+            let input = fd == 0;
+            prepared.child[fd] = match &stdio.0 {
+                Repr::Inherit => None,
+                Repr::Null => {
+                    let file = OpenOptions::new()
+                        .read(input)
+                        .write(!input)
+                        .open(NULL_DEVICE)?;
+                    let mode = if input {
+                        Mode::ReadOnly
+                    } else {
+                        Mode::WriteOnly
+                    };
+                    Some(ChildEnd::Owned(FileDescriptor::from_raw_handle(
+                        file, mode,
+                    )?))
+                }
+                Repr::Piped => {
+                    let (rx, tx) = pipe(PipeMode::new(), 0)?;
+                    let (child, parent) = if input { (rx, tx) } else { (tx, rx) };
+                    prepared.parent[fd] = Some(parent);
+                    Some(ChildEnd::Owned(child))
+                }
+                Repr::Fd(fd) => Some(ChildEnd::Borrowed(fd)),
+            };
+        }
+        Ok(prepared)
+    }
+
+    /// `base` with the child's fd 0, 1 and 2 added. Later mappings in `base` win.
+    pub(crate) fn fd_map<'b>(&'b self, base: &FdMap<'_>) -> FdMap<'b> {
+        let mut map = FdMap::new();
+        for (dest, end) in self.child.iter().enumerate() {
+            if let Some(end) = end {
+                // kept open by self
+                map.insert_raw(dest as c_int, end.as_raw_fd());
+            }
+        }
+        for (dest, src) in base.iter() {
+            map.insert_raw(dest, src);
+        }
+        map
+    }
+}
+
+macro_rules! child_pipe {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Debug)]
+        pub struct $name(FileDescriptor);
+
+        impl $name {
+            pub(crate) fn new(fd: FileDescriptor) -> Self {
+                Self(fd)
+            }
+
+            /// Borrow the pipe end.
+            pub fn as_fd(&self) -> &FileDescriptor {
+                &self.0
+            }
+
+            /// Into the pipe end.
+            pub fn into_fd(self) -> FileDescriptor {
+                self.0
+            }
+        }
+    };
+}
+
+child_pipe! {
+    /// Parent end of the child's stdin. Dropping closes it.
+    ChildStdin
+}
+
+child_pipe! {
+    /// Parent end of the child's stdout.
+    ChildStdout
+}
+
+child_pipe! {
+    /// Parent end of the child's stderr.
+    ChildStderr
+}
+
+impl Write for ChildStdin {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ChildStdout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Read for ChildStderr {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+/// Result of [`Command::output`](crate::Command::output) or [`Child::wait_with_output`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// Exit status of the child.
+    pub status: ExitStatus,
+    /// Everything the child wrote to stdout, if piped.
+    pub stdout: Vec<u8>,
+    /// Everything the child wrote to stderr, if piped.
+    pub stderr: Vec<u8>,
+}
+
+impl Child {
+    /// Close stdin, read stdout and stderr to the end and wait for exit.
+    ///
+    /// Only the pipes still on [`Child::stdout`] and [`Child::stderr`] are read,
+    /// both at the same time.
+    pub fn wait_with_output(mut self) -> io::Result<Output> {
+        drop(self.stdin.take());
+        let (stdout, stderr) = read_both(self.stdout.take(), self.stderr.take())?;
+        let status = self.wait()?;
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Read both to the end, `stderr` on another thread.
+fn read_both(
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    fn read_to_end<R: Read>(r: Option<R>) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        if let Some(mut r) = r {
+            r.read_to_end(&mut buf)?;
+        }
+        Ok(buf)
+    }
+
+    let stderr = stderr.map(|stderr| thread::spawn(move || read_to_end(Some(stderr))));
+    let stdout = read_to_end(stdout);
+    let stderr = match stderr {
+        Some(handle) => handle
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))?,
+        None => vec![],
+    };
+    Ok((stdout?, stderr))
+}
@@ -0,0 +1,238 @@
+//! [`FileDescriptor`] for the tokio runtime.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::FileDescriptor;
+
+/// Asynchronous [`FileDescriptor`], such as the parent's end of a [`pipe`](crate::pipe).
+///
+/// Implements tokio's [`AsyncRead`] and [`AsyncWrite`]. Must be created inside a
+/// tokio runtime.
+///
+/// On Unix the fd is switched to nonblocking mode and polled by the reactor.
+/// On Windows anonymous pipes can not be polled, so the handle of the fd
+/// (`_get_osfhandle`) is read and written on tokio's blocking threads.
+/// Flush before dropping to be sure written data is passed on.
+///
+/// # Example
+///
+/// ```rust
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> std::io::Result<()> {
+/// use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// use winspawn::{pipe, PipeMode, TokioFd};
+///
+/// let (rx, tx) = pipe(PipeMode::new(), 0)?;
+/// let mut rx = TokioFd::new(rx)?;
+/// let mut tx = TokioFd::new(tx)?;
+/// tx.write_all(b"hello").await?;
+/// tx.shutdown().await?;
+/// drop(tx);
+///
+/// let mut buf = String::new();
+/// rx.read_to_string(&mut buf).await?;
+/// assert_eq!("hello", buf);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TokioFd(imp::Inner);
+
+impl TokioFd {
+    /// Take ownership of `fd`.
+    pub fn new(fd: FileDescriptor) -> io::Result<Self> {
+        imp::Inner::new(fd).map(Self)
+    }
+}
+
+impl AsyncRead for TokioFd {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TokioFd {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::pin::Pin;
+    use std::task::{ready, Context, Poll};
+
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use crate::FileDescriptor;
+
+    #[derive(Debug)]
+    struct Fd(FileDescriptor);
+
+    impl AsRawFd for Fd {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    #[derive(Debug)]
+    pub(super) struct Inner(AsyncFd<Fd>);
+
+    impl Inner {
+        pub(super) fn new(fd: FileDescriptor) -> io::Result<Self> {
+            crate::unix::set_nonblocking(fd.as_raw_fd())?;
+            AsyncFd::new(Fd(fd)).map(Self)
+        }
+    }
+
+    impl AsyncRead for Inner {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            loop {
+                let mut guard = ready!(self.0.poll_read_ready(cx))?;
+                let unfilled = buf.initialize_unfilled();
+                match guard.try_io(|fd| fd.get_ref().0.read(unfilled)) {
+                    Ok(result) => {
+                        let n = result?;
+                        buf.advance(n);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for Inner {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            loop {
+                let mut guard = ready!(self.0.poll_write_ready(cx))?;
+                match guard.try_io(|fd| fd.get_ref().0.write(buf)) {
+                    Ok(result) => return Poll::Ready(result),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::fs::File;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use crate::FileDescriptor;
+
+    #[derive(Debug)]
+    pub(super) struct Inner(File);
+
+    impl Inner {
+        pub(super) fn new(fd: FileDescriptor) -> io::Result<Self> {
+            fd.into_file().map(|file| Self(File::from_std(file)))
+        }
+    }
+
+    impl AsyncRead for Inner {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Inner {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{Command, Stdio};
+
+    #[tokio::test]
+    async fn test_child_pipes() {
+        let mut child = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = TokioFd::new(child.stdin.take().unwrap().into_fd()).unwrap();
+        let mut stdout = TokioFd::new(child.stdout.take().unwrap().into_fd()).unwrap();
+
+        // more than the pipe buffers, so both sides wait for readiness
+        let data = vec![b'x'; 1 << 20];
+        let write = async {
+            stdin.write_all(&data).await.unwrap();
+            stdin.shutdown().await.unwrap();
+            drop(stdin);
+        };
+        let mut buf = vec![];
+        let read = stdout.read_to_end(&mut buf);
+        let ((), n) = tokio::join!(write, read);
+        assert_eq!(data.len(), n.unwrap());
+        assert_eq!(data, buf);
+        assert!(child.wait().unwrap().success());
+    }
+}
@@ -0,0 +1,1186 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::mem::{self, ManuallyDrop};
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::IntoRawFd;
+use std::path::Path;
+use std::ptr;
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::plan::{self, Op, Slot};
+use crate::stdio::{ChildStderr, ChildStdin, ChildStdout};
+use crate::wait::ExitNotify;
+use crate::{Command, DropPolicy, ExitStatus, FdMap, Mode, PipeMode, Wait};
+
+fn cvt(ret: c_int) -> io::Result<c_int> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn cvt_r<F: FnMut() -> c_int>(mut f: F) -> io::Result<c_int> {
+    loop {
+        match cvt(f()) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            other => return other,
+        }
+    }
+}
+
+/// Unix File Descriptor.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileDescriptor(c_int);
+
+impl FileDescriptor {
+    /// Construct FileDescriptor from owned fd.
+    ///
+    /// `mode` is for compatibility with Windows. The fd keeps its own access mode.
+    pub fn from_raw_handle<H>(handle: H, _mode: Mode) -> io::Result<Self>
+    where
+        H: IntoRawFd,
+    {
+        Ok(Self(handle.into_raw_fd()))
+    }
+
+    /// Construct FileDescriptor from raw fd.
+    ///
+    /// # Safety
+    /// - Must valid file descriptor
+    /// - No other uses this file descriptor
+    pub unsafe fn from_raw_fd(fd: c_int) -> Self {
+        Self(fd)
+    }
+
+    /// Borrow raw file descriptor.
+    pub fn as_raw_fd(&self) -> c_int {
+        self.0
+    }
+
+    /// Into raw file descriptor.
+    pub fn into_raw_fd(self) -> c_int {
+        let r = self.0;
+        mem::forget(self);
+        r
+    }
+
+    /// Duplicate File Descriptor. (`dup`)
+    pub fn dup(&self) -> io::Result<Self> {
+        let ret = cvt(unsafe { libc::dup(self.0) })?;
+        Ok(Self(ret))
+    }
+
+    /// Duplicate File Descriptor. (`dup2`)
+    pub fn dup2(&self, dest: c_int) -> io::Result<Self> {
+        cvt_r(|| unsafe { libc::dup2(self.0, dest) })?;
+        Ok(Self(dest))
+    }
+
+    /// Duplicate to the lowest fd not less than `min`, not inherited by child processes.
+    fn dup_cloexec(&self, min: c_int) -> io::Result<Self> {
+        let ret = cvt(unsafe { libc::fcntl(self.0, libc::F_DUPFD_CLOEXEC, min) })?;
+        Ok(Self(ret))
+    }
+
+    pub(crate) fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(isize::MAX as usize);
+        loop {
+            let ret = unsafe { libc::read(self.0, buf.as_mut_ptr() as *mut _, len) };
+            if ret >= 0 {
+                return Ok(ret as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    pub(crate) fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(isize::MAX as usize);
+        loop {
+            let ret = unsafe { libc::write(self.0, buf.as_ptr() as *const _, len) };
+            if ret >= 0 {
+                return Ok(ret as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl Drop for FileDescriptor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+pub(crate) fn is_open(fd: c_int) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) >= 0 }
+}
+
+fn set_cloexec(fd: c_int, cloexec: bool) -> io::Result<bool> {
+    let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+    let new = if cloexec {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if new != flags {
+        cvt(unsafe { libc::fcntl(fd, libc::F_SETFD, new) })?;
+    }
+    Ok(flags & libc::FD_CLOEXEC != 0)
+}
+
+#[cfg_attr(not(any(feature = "tokio", feature = "async-io")), allow(dead_code))]
+pub(crate) fn set_nonblocking(fd: c_int) -> io::Result<()> {
+    let flags = cvt(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+    if flags & libc::O_NONBLOCK == 0 {
+        cvt(unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+    }
+    Ok(())
+}
+
+fn cloexec_pipe() -> io::Result<(FileDescriptor, FileDescriptor)> {
+    let mut fds = [0; 2];
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+
+    let pipe = (FileDescriptor(fds[0]), FileDescriptor(fds[1]));
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        set_cloexec(pipe.0 .0, true)?;
+        set_cloexec(pipe.1 .0, true)?;
+    }
+    Ok(pipe)
+}
+
+/// Create an anonymous pipe. (`pipe2`)
+///
+/// Returns the read end and the write end. `size` is the buffer size
+/// (`F_SETPIPE_SZ`, Linux only), 0 for the system default.
+pub fn pipe(mode: PipeMode, size: u32) -> io::Result<(FileDescriptor, FileDescriptor)> {
+    let pipe = cloexec_pipe()?;
+    if mode.is_inheritable() {
+        set_cloexec(pipe.0 .0, false)?;
+        set_cloexec(pipe.1 .0, false)?;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if size > 0 {
+        use std::convert::TryFrom;
+        let size =
+            c_int::try_from(size).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        cvt(unsafe { libc::fcntl(pipe.1 .0, libc::F_SETPIPE_SZ, size) })?;
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = size;
+    Ok(pipe)
+}
+
+/// Lowest fd above every fd in `map`.
+fn temp_min(map: &FdMap<'_>) -> c_int {
+    map.iter()
+        .map(|(dest, src)| dest.max(src))
+        .max()
+        .map_or(0, |max| max + 1)
+}
+
+/// `dup` to an fd above every one of `reserved`, not inherited by child processes.
+pub(crate) fn dup_avoiding(
+    fd: &FileDescriptor,
+    reserved: &BTreeSet<c_int>,
+) -> io::Result<FileDescriptor> {
+    let min = reserved.iter().next_back().map_or(0, |max| max + 1);
+    fd.dup_cloexec(min)
+}
+
+/// Clear close-on-exec. `true` if it was set.
+pub(crate) fn set_inheritable(fd: c_int, _: &BTreeSet<c_int>) -> io::Result<bool> {
+    set_cloexec(fd, false)
+}
+
+/// Set close-on-exec again.
+pub(crate) fn set_noinherit(fd: c_int) -> io::Result<()> {
+    set_cloexec(fd, true).map(drop)
+}
+
+/// Watches a child process and notifies [`ExitNotify`] when it exits.
+///
+/// Does not reap the process. Dropping stops watching and releases the notify.
+#[derive(Debug)]
+pub(crate) struct Waiter {
+    notify: ExitNotify,
+    watch: Option<Watch>,
+}
+
+#[derive(Debug)]
+enum Watch {
+    /// A thread polling the pidfd, stopped by closing `cancel`.
+    Pidfd {
+        cancel: FileDescriptor,
+        thread: thread::JoinHandle<()>,
+    },
+    /// Registered to the `SIGCHLD` dispatcher.
+    Sigchld,
+}
+
+impl Waiter {
+    /// Start watching by `pidfd` if available, otherwise by `SIGCHLD`.
+    fn start(pid: libc::pid_t, pidfd: Option<&FileDescriptor>) -> io::Result<Self> {
+        let notify = ExitNotify::new();
+        let watch = match pidfd {
+            Some(pidfd) => Self::watch_pidfd(pidfd, notify.clone())?,
+            None => {
+                sigchld::register(pid, notify.clone())?;
+                Watch::Sigchld
+            }
+        };
+        Ok(Self {
+            notify,
+            watch: Some(watch),
+        })
+    }
+
+    fn watch_pidfd(pidfd: &FileDescriptor, notify: ExitNotify) -> io::Result<Watch> {
+        let pidfd = pidfd.dup_cloexec(0)?;
+        let (cancel_rx, cancel) = cloexec_pipe()?;
+        let thread = thread::Builder::new()
+            .name("winspawn-waiter".into())
+            .spawn(move || {
+                let mut fds = [
+                    libc::pollfd {
+                        fd: pidfd.0,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    },
+                    libc::pollfd {
+                        fd: cancel_rx.0,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    },
+                ];
+                if let Err(err) = cvt_r(|| unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) }) {
+                    log::warn!("failed to poll pidfd: {}", err);
+                }
+                if fds[0].revents != 0 {
+                    notify.notify();
+                }
+            })?;
+        Ok(Watch::Pidfd { cancel, thread })
+    }
+
+    /// See [`ExitNotify::register`].
+    pub(crate) fn register(&self, waker: &Waker) -> bool {
+        self.notify.register(waker)
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        match self.watch.take() {
+            Some(Watch::Pidfd { cancel, thread }) => {
+                drop(cancel);
+                // returns right away, the thread wakes on the closed pipe
+                if thread.join().is_err() {
+                    log::warn!("waiter thread panicked");
+                }
+            }
+            Some(Watch::Sigchld) => sigchld::unregister(&self.notify),
+            None => {}
+        }
+    }
+}
+
+/// Notifies children without pidfd from a `SIGCHLD` handler.
+///
+/// The handler only writes to a pipe, then calls the previous handler. A dispatcher
+/// thread checks the registered children on each signal.
+mod sigchld {
+    use std::io;
+    use std::mem;
+    use std::os::raw::{c_int, c_void};
+    use std::ptr;
+    use std::sync::atomic::{AtomicI32, AtomicPtr, Ordering};
+    use std::sync::{Mutex, MutexGuard, Once};
+    use std::thread;
+
+    use super::{cloexec_pipe, cvt, cvt_r, FileDescriptor};
+    use crate::wait::ExitNotify;
+
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+    static OLD: AtomicPtr<libc::sigaction> = AtomicPtr::new(ptr::null_mut());
+    static REGISTERED: Mutex<Vec<(libc::pid_t, ExitNotify)>> = Mutex::new(Vec::new());
+
+    fn registered() -> MutexGuard<'static, Vec<(libc::pid_t, ExitNotify)>> {
+        REGISTERED.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Notify `notify` once `pid` exits.
+    pub(super) fn register(pid: libc::pid_t, notify: ExitNotify) -> io::Result<()> {
+        static INSTALL: Once = Once::new();
+        let mut result = Ok(());
+        INSTALL.call_once(|| result = install());
+        result?;
+        if PIPE.load(Ordering::SeqCst) < 0 {
+            return Err(io::Error::other("SIGCHLD handler not installed"));
+        }
+
+        registered().push((pid, notify));
+        // exited before registered
+        dispatch();
+        Ok(())
+    }
+
+    /// Forget `notify` if not notified yet.
+    pub(super) fn unregister(notify: &ExitNotify) {
+        let removed = {
+            let mut registered = registered();
+            let index = registered.iter().position(|(_, n)| n.ptr_eq(notify));
+            index.map(|i| registered.swap_remove(i))
+        };
+        // may drop a waker, not under the lock
+        drop(removed);
+    }
+
+    fn install() -> io::Result<()> {
+        let (rx, tx) = cloexec_pipe()?;
+        let flags = cvt(unsafe { libc::fcntl(tx.0, libc::F_GETFL) })?;
+        // a full pipe already wakes the dispatcher
+        cvt(unsafe { libc::fcntl(tx.0, libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+
+        thread::Builder::new()
+            .name("winspawn-sigchld".into())
+            .spawn(move || run(rx))?;
+
+        let mut old = Box::new(unsafe { mem::zeroed::<libc::sigaction>() });
+        let mut action = unsafe { mem::zeroed::<libc::sigaction>() };
+        let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = handler;
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+        cvt(unsafe { libc::sigaction(libc::SIGCHLD, ptr::null(), &mut *old) })?;
+        OLD.store(Box::into_raw(old), Ordering::SeqCst);
+        PIPE.store(tx.into_raw_fd(), Ordering::SeqCst);
+        if let Err(err) = cvt(unsafe { libc::sigaction(libc::SIGCHLD, &action, ptr::null_mut()) }) {
+            // also stops the dispatcher
+            let tx = PIPE.swap(-1, Ordering::SeqCst);
+            drop(unsafe { FileDescriptor::from_raw_fd(tx) });
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    extern "C" fn handler(signum: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+        let errno = io::Error::last_os_error().raw_os_error();
+        let fd = PIPE.load(Ordering::SeqCst);
+        unsafe { libc::write(fd, [0u8].as_ptr() as *const c_void, 1) };
+
+        let old = OLD.load(Ordering::SeqCst);
+        if let Some(old) = unsafe { old.as_ref() } {
+            let action = old.sa_sigaction;
+            if action != libc::SIG_DFL && action != libc::SIG_IGN {
+                unsafe {
+                    if old.sa_flags & libc::SA_SIGINFO != 0 {
+                        let f: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                            mem::transmute(action);
+                        f(signum, info, context);
+                    } else {
+                        let f: extern "C" fn(c_int) = mem::transmute(action);
+                        f(signum);
+                    }
+                }
+            }
+        }
+
+        if let Some(errno) = errno {
+            set_errno(errno);
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn set_errno(errno: c_int) {
+        unsafe { *libc::__errno_location() = errno };
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn set_errno(errno: c_int) {
+        unsafe { *libc::__error() = errno };
+    }
+
+    fn run(rx: FileDescriptor) {
+        let mut buf = [0u8; 64];
+        loop {
+            match cvt_r(|| unsafe {
+                libc::read(rx.0, buf.as_mut_ptr() as *mut c_void, buf.len()) as c_int
+            }) {
+                Ok(0) => return,
+                Ok(_) => dispatch(),
+                Err(err) => {
+                    log::warn!("failed to read SIGCHLD pipe: {}", err);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Notify and forget every exited child.
+    fn dispatch() {
+        let exited = {
+            let mut registered = registered();
+            let mut exited = vec![];
+            let mut i = 0;
+            while i < registered.len() {
+                if has_exited(registered[i].0) {
+                    exited.push(registered.swap_remove(i).1);
+                } else {
+                    i += 1;
+                }
+            }
+            exited
+        };
+        for notify in exited {
+            notify.notify();
+        }
+    }
+
+    /// Without reaping. Also `true` if already reaped.
+    fn has_exited(pid: libc::pid_t) -> bool {
+        let mut info = unsafe { mem::zeroed::<libc::siginfo_t>() };
+        let ret = cvt_r(|| unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+            )
+        });
+        match ret {
+            Ok(_) => (unsafe { info.si_pid() }) != 0,
+            Err(_) => true,
+        }
+    }
+}
+
+/// `None` if not supported.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn pidfd_open(pid: libc::pid_t) -> io::Result<Option<FileDescriptor>> {
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOSYS) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    Ok(Some(FileDescriptor(ret as c_int)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn pidfd_open(_: libc::pid_t) -> io::Result<Option<FileDescriptor>> {
+    Ok(None)
+}
+
+/// Represent child process.
+///
+/// An instance is a Future that represents an asynchronous termination.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io;
+///
+/// #[tokio::main(flavor = "current_thread")]
+/// async fn main() -> io::Result<()> {
+///     let mut proc = winspawn::spawn("cargo", ["--version"])?;
+///     let status = proc.await?;
+///     assert!(status.success());
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Child {
+    pid: libc::pid_t,
+    // Linux only. waits without polling if available.
+    pidfd: Option<FileDescriptor>,
+    status: Option<c_int>,
+    pub(crate) waiter: Option<Waiter>,
+    pub(crate) drop_policy: DropPolicy,
+    /// Parent end of the child's stdin, if [`Stdio::piped`](crate::Stdio::piped).
+    pub stdin: Option<ChildStdin>,
+    /// Parent end of the child's stdout, if [`Stdio::piped`](crate::Stdio::piped).
+    pub stdout: Option<ChildStdout>,
+    /// Parent end of the child's stderr, if [`Stdio::piped`](crate::Stdio::piped).
+    pub stderr: Option<ChildStderr>,
+}
+
+impl Child {
+    /// Construct from the pid of a child process of this process.
+    ///
+    /// # Safety
+    /// - Must be a child process not reaped yet
+    /// - No other waits for this process
+    pub unsafe fn from_raw_pid(pid: libc::pid_t) -> Self {
+        Self {
+            pid,
+            pidfd: pidfd_open(pid).ok().flatten(),
+            status: None,
+            waiter: None,
+            drop_policy: DropPolicy::default(),
+            stdin: None,
+            stdout: None,
+            stderr: None,
+        }
+    }
+
+    /// Into the pid without waiting. The caller is responsible to reap the process.
+    ///
+    /// The drop policy is not applied.
+    pub fn into_raw_pid(self) -> libc::pid_t {
+        let mut this = ManuallyDrop::new(self);
+        drop(this.waiter.take());
+        drop(this.pidfd.take());
+        drop((this.stdin.take(), this.stdout.take(), this.stderr.take()));
+        this.pid
+    }
+
+    /// Process id.
+    pub fn id(&self) -> u32 {
+        self.pid as u32
+    }
+
+    /// `pidfd` of the process. (Linux)
+    ///
+    /// `None` if the kernel does not support it. Owned by `self` and close-on-exec.
+    pub fn pidfd(&self) -> Option<c_int> {
+        self.pidfd.as_ref().map(FileDescriptor::as_raw_fd)
+    }
+
+    /// Synchronous wait for exit.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        if let Some(status) = self.status {
+            return Ok(ExitStatus::from_wait_status(status));
+        }
+
+        let mut status = 0;
+        cvt_r(|| unsafe { libc::waitpid(self.pid, &mut status, 0) })?;
+        self.status = Some(status);
+        Ok(ExitStatus::from_wait_status(status))
+    }
+
+    /// Try wait for exit.
+    ///
+    /// Return immediately. If the process is finished, the exit code can be acquired.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        if let Some(status) = self.status {
+            return Ok(Some(ExitStatus::from_wait_status(status)));
+        }
+
+        let mut status = 0;
+        let pid = cvt_r(|| unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) })?;
+        if pid == 0 {
+            return Ok(None);
+        }
+        self.status = Some(status);
+        Ok(Some(ExitStatus::from_wait_status(status)))
+    }
+
+    /// Wait for exit until `deadline`.
+    ///
+    /// Return `None` if the process is still running.
+    pub fn wait_deadline(&mut self, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+        if let Some(status) = self.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if let Some(pidfd) = self.pidfd() {
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // round up not to return before the deadline
+                let millis = remaining.as_nanos().div_ceil(1_000_000);
+                let millis = millis.min(c_int::MAX as u128) as c_int;
+
+                let mut fds = [libc::pollfd {
+                    fd: pidfd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                }];
+                cvt_r(|| unsafe { libc::poll(fds.as_mut_ptr(), 1, millis) })?;
+                if let Some(status) = self.try_wait()? {
+                    return Ok(Some(status));
+                }
+                if Instant::now() >= deadline {
+                    return Ok(None);
+                }
+            }
+        }
+
+        // no way to wait with timeout. poll with backoff.
+        let mut interval = Duration::from_millis(1);
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(interval.min(deadline - now));
+            interval = (interval * 2).min(Duration::from_millis(50));
+
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+        }
+    }
+
+    /// Asynchronous wait for exit without consuming `self`.
+    ///
+    /// Dropping the future does not affect the process, so it can be raced with a timer.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::io;
+    /// use std::time::Duration;
+    ///
+    /// #[tokio::main(flavor = "current_thread")]
+    /// async fn main() -> io::Result<()> {
+    ///     let mut proc = winspawn::spawn("python", ["-c", "import time; time.sleep(60)"])?;
+    ///     let result = tokio::time::timeout(Duration::from_millis(100), proc.wait_async()).await;
+    ///     assert!(result.is_err());
+    ///     proc.kill()?;
+    ///     assert!(!proc.wait_async().await?.success());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn wait_async(&mut self) -> Wait<'_> {
+        Wait(self)
+    }
+
+    /// Terminate process. (`SIGKILL`)
+    ///
+    /// The exit status reports [`ExitStatus::signal`] as `SIGKILL`, and
+    /// [`ExitStatus::killed`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::io;
+    /// use winspawn::spawn;
+    ///
+    /// fn main() -> io::Result<()> {
+    ///     let mut proc = spawn("python", ["-c", r#"import time; time.sleep(0xFFFFFFFF)"#])?;
+    ///     proc.kill()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn kill(&mut self) -> io::Result<()> {
+        if self.status.is_some() {
+            // already reaped. pid may be reused.
+            return Ok(());
+        }
+        cvt(unsafe { libc::kill(self.pid, libc::SIGKILL) }).map(drop)
+    }
+
+    /// Start watching on the first poll.
+    pub(crate) fn waiter(&mut self) -> io::Result<&Waiter> {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => Waiter::start(self.pid, self.pidfd.as_ref())?,
+        };
+        Ok(self.waiter.insert(waiter))
+    }
+
+    /// Send `SIGTERM`.
+    pub(crate) fn request_stop(&mut self) -> io::Result<()> {
+        cvt(unsafe { libc::kill(self.pid, libc::SIGTERM) }).map(drop)
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        self.stop_on_drop();
+    }
+}
+
+fn cstring<S: AsRef<OsStr>>(s: S) -> io::Result<CString> {
+    CString::new(s.as_ref().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Candidate paths of `program` as `execvp` searches `PATH`.
+fn program_candidates(program: &OsStr, path: Option<OsString>) -> io::Result<Vec<CString>> {
+    if program.as_bytes().contains(&b'/') {
+        return Ok(vec![cstring(program)?]);
+    }
+
+    let path = path.unwrap_or_else(|| OsString::from("/bin:/usr/bin"));
+    env::split_paths(&path)
+        .map(|dir| {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                &dir
+            };
+            cstring(dir.join(program))
+        })
+        .collect()
+}
+
+/// call `fork` & `execve`.
+///
+/// All File Descriptors that do not have the close-on-exec flag will be inherited by the child process.
+pub fn spawn<P, A, AS>(program: P, args: A) -> io::Result<Child>
+where
+    P: AsRef<OsStr>,
+    A: IntoIterator<Item = AS>,
+    AS: AsRef<OsStr>,
+{
+    Command::new(program).args(args).spawn()
+}
+
+pub(crate) fn spawn_command(cmd: &Command<'_>, map: &FdMap<'_>) -> io::Result<Child> {
+    // everything the child uses is prepared before fork.
+    let argv = cmd
+        .argv()
+        .into_iter()
+        .map(cstring)
+        .collect::<io::Result<Vec<_>>>()?;
+    let argv_ptr = argv
+        .iter()
+        .map(|arg| arg.as_ptr())
+        .chain(Some(ptr::null()))
+        .collect::<Vec<_>>();
+
+    let env = cmd
+        .capture_env()
+        .unwrap_or_else(|| env::vars_os().collect());
+    let path = env
+        .iter()
+        .find(|(k, _)| k == "PATH")
+        .map(|(_, v)| v.clone());
+    let env = env
+        .into_iter()
+        .map(|(mut key, val)| {
+            key.push("=");
+            key.push(val);
+            cstring(key)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let env_ptr = env
+        .iter()
+        .map(|var| var.as_ptr())
+        .chain(Some(ptr::null()))
+        .collect::<Vec<_>>();
+
+    let candidates = program_candidates(cmd.get_program(), path)?;
+    let current_dir = cmd.get_current_dir().map(cstring).transpose()?;
+
+    let ops = plan::plan(map.iter());
+    let min = temp_min(map);
+    let mut temps = vec![-1; ops.len()];
+
+    // reports exec failure. must not be any of the targets.
+    let (rx, tx) = cloexec_pipe()?;
+    let reserved = map.iter().map(|(dest, _)| dest).collect::<BTreeSet<_>>();
+    let tx = if reserved.contains(&tx.0) {
+        tx.dup_cloexec(min)?
+    } else {
+        tx
+    };
+
+    let pid = cvt(unsafe { libc::fork() })?;
+    if pid == 0 {
+        // child: async-signal-safe only.
+        let err = unsafe {
+            exec_child(
+                &ops,
+                &mut temps,
+                min,
+                current_dir.as_ref(),
+                &candidates,
+                &argv_ptr,
+                &env_ptr,
+            )
+        };
+        let code = err.raw_os_error().unwrap_or(0).to_ne_bytes();
+        unsafe {
+            libc::write(tx.0, code.as_ptr() as *const _, code.len());
+            libc::_exit(127)
+        }
+    }
+    drop(tx);
+
+    let pidfd = pidfd_open(pid).unwrap_or_else(|err| {
+        log::debug!("failed to open pidfd: {}", err);
+        None
+    });
+    let mut child = Child {
+        pid,
+        pidfd,
+        status: None,
+        waiter: None,
+        drop_policy: DropPolicy::default(),
+        stdin: None,
+        stdout: None,
+        stderr: None,
+    };
+
+    let mut code = [0u8; 4];
+    let mut n = 0;
+    while n < code.len() {
+        let ret = unsafe { libc::read(rx.0, code[n..].as_mut_ptr() as *mut _, code.len() - n) };
+        match ret {
+            0 => break,
+            ret if ret < 0 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            ret => n += ret as usize,
+        }
+    }
+
+    if n == 0 {
+        return Ok(child);
+    }
+    child.wait()?;
+    Err(io::Error::from_raw_os_error(c_int::from_ne_bytes(code)))
+}
+
+/// Runs in the forked child. Returns only on failure.
+unsafe fn exec_child(
+    ops: &[Op],
+    temps: &mut [c_int],
+    min: c_int,
+    current_dir: Option<&CString>,
+    candidates: &[CString],
+    argv: &[*const libc::c_char],
+    envp: &[*const libc::c_char],
+) -> io::Error {
+    macro_rules! t {
+        ($e:expr) => {
+            match $e {
+                Ok(r) => r,
+                Err(err) => return err,
+            }
+        };
+    }
+
+    for op in ops {
+        match *op {
+            Op::Save { fd, temp } => {
+                temps[temp] = t!(cvt(libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, min)));
+            }
+            Op::Dup2 { src, dest } => {
+                let src = match src {
+                    Slot::Fd(fd) => fd,
+                    Slot::Temp(temp) => temps[temp],
+                };
+                t!(cvt_r(|| libc::dup2(src, dest)));
+            }
+            Op::Inherit(fd) => {
+                t!(set_cloexec(fd, false));
+            }
+            Op::Close(temp) => {
+                libc::close(temps[temp]);
+            }
+        }
+    }
+
+    if let Some(dir) = current_dir {
+        t!(cvt(libc::chdir(dir.as_ptr())));
+    }
+
+    // Rust ignores SIGPIPE. restore default for the child.
+    libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+
+    let mut err = io::Error::from_raw_os_error(libc::ENOENT);
+    for path in candidates {
+        libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::ENOENT) | Some(libc::ENOTDIR) => {}
+            // keep the most meaningful one as execvp does.
+            Some(libc::EACCES) => err = e,
+            _ => return e,
+        }
+    }
+    err
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::FromRawFd;
+
+    fn inode(fd: c_int) -> u64 {
+        let file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+        file.metadata().unwrap().ino()
+    }
+
+    #[test]
+    fn test_apply_swap() {
+        let a =
+            FileDescriptor::from_raw_handle(fs::File::open("Cargo.toml").unwrap(), Mode::ReadOnly)
+                .unwrap()
+                .dup2(100)
+                .unwrap();
+        let b = FileDescriptor::from_raw_handle(fs::File::open("src").unwrap(), Mode::ReadOnly)
+            .unwrap()
+            .dup2(101)
+            .unwrap();
+        let (ia, ib) = (inode(100), inode(101));
+
+        FdMap::new()
+            .insert(100, &b)
+            .insert(101, &a)
+            .insert(102, &a)
+            .apply(|| {
+                assert_eq!((ib, ia, ia), (inode(100), inode(101), inode(102)));
+                io::Result::Ok(())
+            })
+            .unwrap();
+
+        assert_eq!((ia, ib), (inode(100), inode(101)));
+        assert!(!is_open(102));
+    }
+
+    #[test]
+    fn test_not_found() {
+        let err = spawn("./no-such-program", ["x"]).unwrap_err();
+        assert_eq!(Some(libc::ENOENT), err.raw_os_error());
+    }
+
+    #[test]
+    fn test_current_dir() {
+        let before = env::current_dir().unwrap();
+        let mut child = Command::new("./bin/sh")
+            .args(["-c", r#"test "$(pwd -P)" = /"#])
+            .current_dir("/")
+            .spawn()
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(before, env::current_dir().unwrap());
+
+        let err = Command::new("sh")
+            .current_dir("/no-such-dir")
+            .spawn()
+            .unwrap_err();
+        assert_eq!(Some(libc::ENOENT), err.raw_os_error());
+    }
+
+    #[test]
+    fn test_wait() {
+        let mut child = Command::new("sh").args(["-c", "exit 3"]).spawn().unwrap();
+        assert_eq!(Some(3), child.wait().unwrap().code());
+        assert_eq!(Some(3), child.try_wait().unwrap().unwrap().code());
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let mut child = spawn("sleep", ["10"]).unwrap();
+        let start = Instant::now();
+        assert_eq!(None, child.wait_timeout(Duration::from_millis(50)).unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        child.kill().unwrap();
+        let status = child
+            .wait_timeout(Duration::from_secs(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(libc::SIGKILL), status.signal());
+    }
+
+    #[test]
+    fn test_drop_kill() {
+        let child = Command::new("sleep")
+            .arg("10")
+            .drop_policy(DropPolicy::Kill)
+            .spawn()
+            .unwrap();
+        let pid = child.pid;
+        drop(child);
+
+        // reaped
+        assert_eq!(-1, unsafe { libc::kill(pid, 0) });
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let mut child = spawn("sh", ["-c", "trap 'exit 7' TERM; sleep 10 & wait"]).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let status = child.shutdown(Duration::from_secs(10)).unwrap();
+        assert_eq!(Some(7), status.code());
+
+        let mut child = spawn("sh", ["-c", "trap '' TERM; sleep 1"]).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let status = child.shutdown(Duration::from_millis(50)).unwrap();
+        assert_eq!(Some(libc::SIGKILL), status.signal());
+    }
+
+    #[test]
+    fn test_pipe() {
+        use std::io::Read;
+
+        let (rx, tx) = pipe(PipeMode::new(), 0).unwrap();
+        assert!(set_cloexec(rx.as_raw_fd(), true).unwrap());
+        assert!(set_cloexec(tx.as_raw_fd(), true).unwrap());
+
+        let mut child = Command::new("sh")
+            .args(["-c", "echo hello >&3"])
+            .fd(3, &tx)
+            .spawn()
+            .unwrap();
+        drop(tx);
+        let mut rx = unsafe { std::fs::File::from_raw_fd(rx.into_raw_fd()) };
+        let mut buf = String::new();
+        rx.read_to_string(&mut buf).unwrap();
+        assert_eq!("hello\n", buf);
+        assert!(child.wait().unwrap().success());
+
+        let (rx, _tx) = pipe(PipeMode::new().inheritable(true), 1 << 16).unwrap();
+        assert!(!set_cloexec(rx.as_raw_fd(), false).unwrap());
+        #[cfg(target_os = "linux")]
+        assert!(unsafe { libc::fcntl(rx.as_raw_fd(), libc::F_GETPIPE_SZ) } >= 1 << 16);
+    }
+
+    #[test]
+    fn test_stdio() {
+        use crate::Stdio;
+        use std::io::{Read, Write};
+
+        let mut child = Command::new("sh")
+            .args(["-c", "cat; echo err >&2"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        assert!(child.stdin.is_some());
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"hello").unwrap();
+        drop(stdin);
+        let mut out = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!("hello", out);
+        let mut err = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut err)
+            .unwrap();
+        assert_eq!("err\n", err);
+        assert!(child.wait().unwrap().success());
+
+        // null, and a `Command::fd` on 1 wins over `stdout`
+        let (rx, tx) = pipe(PipeMode::new(), 0).unwrap();
+        let mut child = Command::new("sh")
+            .args(["-c", "cat; echo done"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .fd(1, &tx)
+            .spawn()
+            .unwrap();
+        assert!(child.stdin.is_none() && child.stdout.is_none());
+        drop(tx);
+        let mut buf = String::new();
+        crate::ChildStdout::new(rx)
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!("done\n", buf);
+        assert!(child.wait().unwrap().success());
+
+        // a FileDescriptor, closed in the parent with the command
+        let (rx, tx) = pipe(PipeMode::new(), 0).unwrap();
+        let mut child = Command::new("echo").arg("fd").stdout(tx).spawn().unwrap();
+        let mut buf = String::new();
+        crate::ChildStdout::new(rx)
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!("fd\n", buf);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_output() {
+        // stderr first: a child blocked on a full stderr pipe never closes stdout
+        const SIZE: usize = 4 << 20;
+        let script = format!(
+            "head -c {0} /dev/zero | tr '\\0' e >&2; head -c {0} /dev/zero | tr '\\0' o; exit 3",
+            SIZE
+        );
+        let output = Command::new("sh").args(["-c", &script]).output().unwrap();
+        assert_eq!(Some(3), output.status.code());
+        assert_eq!(SIZE, output.stdout.len());
+        assert!(output.stdout.iter().all(|b| *b == b'o'));
+        assert_eq!(SIZE, output.stderr.len());
+        assert!(output.stderr.iter().all(|b| *b == b'e'));
+
+        // extra fds and explicit stdio
+        let (rx, tx) = pipe(PipeMode::new(), 0).unwrap();
+        drop(tx);
+        let output = Command::new("sh")
+            .args(["-c", "cat <&3; echo out"])
+            .fd(3, &rx)
+            .stderr(crate::Stdio::inherit())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(b"out\n", &output.stdout[..]);
+        assert!(output.stderr.is_empty());
+
+        let status = Command::new("sh").args(["-c", "exit 4"]).status().unwrap();
+        assert_eq!(Some(4), status.code());
+    }
+
+    #[test]
+    fn test_repoll_sigchld() {
+        crate::wait::tests::test_repoll_with(|child| child.pidfd = None);
+    }
+
+    #[test]
+    fn test_raw_pid() {
+        let child = spawn("sh", ["-c", "exit 5"]).unwrap();
+        let pid = child.id();
+        assert!(child.pidfd().is_some());
+
+        let raw = child.into_raw_pid();
+        assert_eq!(pid, raw as u32);
+        let mut child = unsafe { Child::from_raw_pid(raw) };
+        assert_eq!(Some(5), child.wait().unwrap().code());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let path = Some(OsString::from("/a::/b"));
+        assert_eq!(
+            vec![
+                CString::new("/a/python").unwrap(),
+                CString::new("./python").unwrap(),
+                CString::new("/b/python").unwrap(),
+            ],
+            program_candidates(OsStr::new("python"), path.clone()).unwrap()
+        );
+        assert_eq!(
+            vec![CString::new("./bin/python").unwrap()],
+            program_candidates(OsStr::new("./bin/python"), path).unwrap()
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::{Child, ExitStatus};
+
+/// Future returned by [`Child::wait_async`].
+///
+/// Borrows the [`Child`] instead of consuming it, so it can be dropped part way
+/// (e.g. by a timeout) and the process can still be killed or waited for.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Wait<'a>(pub(crate) &'a mut Child);
+
+impl Future for Wait<'_> {
+    type Output = io::Result<ExitStatus>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.0).poll(cx)
+    }
+}
+
+/// What dropping a [`Child`] does to a still running process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DropPolicy {
+    /// Leave the process running.
+    #[default]
+    Detach,
+    /// Kill the process and wait for it.
+    Kill,
+    /// [`Child::shutdown`] with the grace period. Blocks the dropping thread up to it.
+    ///
+    /// On Windows a console process is never asked to stop, it is killed after the
+    /// grace period.
+    Shutdown(Duration),
+}
+
+impl Child {
+    /// Wait for exit up to `timeout`.
+    ///
+    /// Return `None` if the process is still running.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.wait_deadline(deadline),
+            None => self.wait().map(Some),
+        }
+    }
+
+    /// Dropping policy. Default is [`DropPolicy::Detach`].
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
+    }
+
+    /// Current dropping policy.
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.drop_policy
+    }
+
+    /// Ask the process to stop, and kill it if still running after `grace`.
+    ///
+    /// On Unix stop is requested by `SIGTERM`. Windows has no signal: `WM_CLOSE` is
+    /// posted to the top-level windows of the process, and nothing else. A console
+    /// process is never asked to stop, it is killed after `grace`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::io;
+    /// use std::time::Duration;
+    /// use winspawn::spawn;
+    ///
+    /// fn main() -> io::Result<()> {
+    ///     let mut proc = spawn("python", ["-c", "import time; time.sleep(60)"])?;
+    ///     let status = proc.shutdown(Duration::from_millis(100))?;
+    ///     assert!(!status.success());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn shutdown(&mut self, grace: Duration) -> io::Result<ExitStatus> {
+        if let Some(status) = self.try_wait()? {
+            return Ok(status);
+        }
+
+        if let Err(err) = self.request_stop() {
+            log::debug!("failed to request stop: {}", err);
+        }
+        if let Some(status) = self.wait_timeout(grace)? {
+            return Ok(status);
+        }
+
+        self.kill()?;
+        self.wait()
+    }
+
+    /// Apply the drop policy.
+    pub(crate) fn stop_on_drop(&mut self) {
+        let result = match self.drop_policy {
+            DropPolicy::Detach => return,
+            DropPolicy::Kill => match self.try_wait() {
+                Ok(Some(..)) => return,
+                Ok(None) => self.kill().and_then(|_| self.wait()),
+                Err(err) => Err(err),
+            },
+            DropPolicy::Shutdown(grace) => self.shutdown(grace),
+        };
+        if let Err(err) = result {
+            log::warn!("failed to stop child process: {}", err);
+        }
+    }
+}
+
+impl Future for Child {
+    type Output = io::Result<ExitStatus>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = Pin::get_mut(self);
+
+        if let Some(r) = this.try_wait()? {
+            return Poll::Ready(Ok(r));
+        }
+
+        // keeps only this waker, the task may have moved since the last poll
+        this.waiter()?.register(cx.waker());
+
+        // exited before the waker registered
+        if let Some(r) = this.try_wait()? {
+            return Poll::Ready(Ok(r));
+        }
+        Poll::Pending
+    }
+}
+
+/// Exit notification shared between a [`Child`] and whatever watches the process.
+///
+/// Holds the waker of the latest poll only, so a future moved to another task is
+/// still woken, and the waker is dropped with the last clone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExitNotify(Arc<Mutex<NotifyState>>);
+
+#[derive(Debug, Default)]
+struct NotifyState {
+    exited: bool,
+    waker: Option<Waker>,
+}
+
+impl ExitNotify {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, NotifyState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replace the waker by `waker`, unless it wakes the same task.
+    ///
+    /// `true` if [`ExitNotify::notify`] was already called; nothing is stored then.
+    pub(crate) fn register(&self, waker: &Waker) -> bool {
+        let mut state = self.lock();
+        if state.exited {
+            return true;
+        }
+        match &state.waker {
+            Some(old) if old.will_wake(waker) => {}
+            _ => state.waker = Some(waker.clone()),
+        }
+        false
+    }
+
+    /// Clone of the same notify?
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Mark the process exited and wake the latest waker.
+    pub(crate) fn notify(&self) {
+        let waker = {
+            let mut state = self.lock();
+            state.exited = true;
+            state.waker.take()
+        };
+        // not under the lock, the waker may poll right away
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+    use std::thread;
+
+    use crate::{spawn, Command, Stdio};
+
+    /// Reads stdin until EOF.
+    #[cfg(unix)]
+    const CAT: (&str, &[&str]) = ("cat", &[]);
+    #[cfg(windows)]
+    const CAT: (&str, &[&str]) = ("python", &["-c", "import sys; sys.stdin.read()"]);
+
+    #[cfg(unix)]
+    const SLEEP: (&str, &[&str]) = ("sleep", &["10"]);
+    #[cfg(windows)]
+    const SLEEP: (&str, &[&str]) = ("python", &["-c", "import time; time.sleep(10)"]);
+
+    /// Waker counting its wakes.
+    #[derive(Debug, Default)]
+    pub(crate) struct CountWaker(AtomicUsize);
+
+    impl CountWaker {
+        pub(crate) fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_latest_waker() {
+        let (a, b) = (
+            Arc::new(CountWaker::default()),
+            Arc::new(CountWaker::default()),
+        );
+        let notify = ExitNotify::new();
+        assert!(!notify.register(&Waker::from(a.clone())));
+        assert!(!notify.register(&Waker::from(b.clone())));
+        // the replaced waker is dropped
+        assert_eq!(1, Arc::strong_count(&a));
+
+        notify.clone().notify();
+        assert_eq!((0, 1), (a.count(), b.count()));
+        assert_eq!(1, Arc::strong_count(&b));
+        assert!(notify.register(&Waker::from(a.clone())));
+        assert_eq!(1, Arc::strong_count(&a));
+    }
+
+    #[test]
+    fn test_drop_frees_waker() {
+        let a = Arc::new(CountWaker::default());
+        let notify = ExitNotify::new();
+        notify.register(&Waker::from(a.clone()));
+        let clone = notify.clone();
+        drop(notify);
+        assert_eq!(2, Arc::strong_count(&a));
+        drop(clone);
+        assert_eq!(1, Arc::strong_count(&a));
+        assert_eq!(0, a.count());
+    }
+
+    /// Polls by two tasks in turn. `prepare` picks the backend's waiter.
+    pub(crate) fn test_repoll_with(prepare: fn(&mut Child)) {
+        let poll = |child: &mut Child, waker: &Arc<CountWaker>| {
+            let waker = Waker::from(waker.clone());
+            Pin::new(child).poll(&mut Context::from_waker(&waker))
+        };
+
+        let mut child = Command::new(CAT.0)
+            .args(CAT.1)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        prepare(&mut child);
+        let (a, b) = (
+            Arc::new(CountWaker::default()),
+            Arc::new(CountWaker::default()),
+        );
+        assert!(poll(&mut child, &a).is_pending());
+        // as if moved to another task
+        assert!(poll(&mut child, &b).is_pending());
+        assert_eq!(1, Arc::strong_count(&a));
+
+        drop(child.stdin.take());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while b.count() == 0 {
+            assert!(Instant::now() < deadline, "not woken");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!((0, 1), (a.count(), b.count()));
+        match poll(&mut child, &b) {
+            Poll::Ready(status) => assert!(status.unwrap().success()),
+            Poll::Pending => panic!("woken before exit"),
+        }
+
+        // dropping the waiter frees the waker
+        let mut child = spawn(SLEEP.0, SLEEP.1).unwrap();
+        prepare(&mut child);
+        assert!(poll(&mut child, &a).is_pending());
+        assert_eq!(2, Arc::strong_count(&a));
+        drop(child.waiter.take());
+        assert_eq!(1, Arc::strong_count(&a));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(0, a.count());
+    }
+
+    #[test]
+    fn test_repoll() {
+        test_repoll_with(|_| {});
+    }
+}
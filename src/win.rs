@@ -0,0 +1,885 @@
+use std::collections::BTreeSet;
+use std::ffi::{c_void, OsStr};
+use std::fs;
+use std::io;
+use std::iter;
+use std::mem::{self, ManuallyDrop};
+use std::os::raw::{c_int, c_uint};
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, IntoRawHandle, RawHandle};
+use std::ptr;
+use std::task::Waker;
+use std::time::Instant;
+
+use crate::direct::{self, Inherit, Startup};
+use crate::environ::env_block;
+use crate::fdmap::StaticMutex;
+use crate::invalid::{self, InvalidParameterGuard};
+use crate::reserved2::Flags;
+use crate::stdio::{ChildStderr, ChildStdin, ChildStdout};
+use crate::sys::wchar_t;
+use crate::sys::{_close, _dup, _dup2, _errno};
+use crate::sys::{_get_osfhandle, _isatty, _open_osfhandle};
+use crate::sys::{_pipe, _read, _write, O_BINARY, O_NOINHERIT, O_TEXT};
+use crate::sys::{_wspawnvp, _wspawnvpe, P_NOWAIT};
+use crate::sys::{O_RDONLY, O_RDWR, O_WRONLY};
+use crate::wait::ExitNotify;
+use crate::{Command, DropPolicy, Error, ExitStatus, FdMap, Mode, PipeMode, Wait};
+
+use windows::core::{PCWSTR, PWSTR};
+use windows::Win32::Foundation::{
+    CloseHandle, DuplicateHandle, GetHandleInformation, BOOL, BOOLEAN, DUPLICATE_SAME_ACCESS,
+    HANDLE, HANDLE_FLAG_INHERIT, HWND, INVALID_HANDLE_VALUE, LPARAM, WAIT_OBJECT_0, WAIT_TIMEOUT,
+    WPARAM,
+};
+use windows::Win32::Storage::FileSystem::GetFileType;
+use windows::Win32::System::Threading::{
+    CreateProcessW, DeleteProcThreadAttributeList, GetCurrentProcess, GetExitCodeProcess,
+    GetProcessId, InitializeProcThreadAttributeList, RegisterWaitForSingleObject, TerminateProcess,
+    UnregisterWaitEx, UpdateProcThreadAttribute, WaitForSingleObject, CREATE_UNICODE_ENVIRONMENT,
+    EXTENDED_STARTUPINFO_PRESENT, LPPROC_THREAD_ATTRIBUTE_LIST, PROCESS_INFORMATION,
+    PROC_THREAD_ATTRIBUTE_HANDLE_LIST, STARTF_USESTDHANDLES, STARTUPINFOEXW,
+    WT_EXECUTEINWAITTHREAD, WT_EXECUTEONLYONCE,
+};
+use windows::Win32::System::WindowsProgramming::{FILE_TYPE_CHAR, FILE_TYPE_PIPE, INFINITE};
+use windows::Win32::UI::WindowsAndMessaging::{
+    EnumWindows, GetWindowThreadProcessId, PostMessageW, WM_CLOSE,
+};
+
+impl Mode {
+    fn val(&self) -> c_int {
+        match self {
+            Self::ReadOnly => O_RDONLY as c_int,
+            Self::WriteOnly => O_WRONLY as c_int,
+            Self::ReadWrite => O_RDWR as c_int,
+        }
+    }
+}
+
+/// Windows File Descriptor (universal CRT).
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileDescriptor(c_int);
+
+impl FileDescriptor {
+    /// Construct FileDescriptor from Windows File Handle.
+    #[winspawn_macro::ignore_invalid_handler]
+    pub fn from_raw_handle<H>(handle: H, mode: Mode) -> io::Result<Self>
+    where
+        H: IntoRawHandle,
+    {
+        let handle = handle.into_raw_handle();
+        let r = unsafe { _open_osfhandle(handle as isize, mode.val()) };
+        if r < 0 {
+            return Err(crt_error("_open_osfhandle", None));
+        }
+        Ok(Self(r))
+    }
+
+    /// Construct FileDescriptor from raw fd.
+    ///
+    /// # Safety
+    /// - Must valid file descriptor
+    /// - No other uses this file descriptor
+    pub unsafe fn from_raw_fd(fd: c_int) -> Self {
+        Self(fd)
+    }
+
+    /// Borrow raw file descriptor.
+    pub fn as_raw_fd(&self) -> c_int {
+        self.0
+    }
+
+    /// Into raw file descriptor.
+    pub fn into_raw_fd(self) -> c_int {
+        let r = self.0;
+        mem::forget(self);
+        r
+    }
+
+    /// Duplicate File Descriptor. (`_dup`)
+    #[winspawn_macro::ignore_invalid_handler]
+    pub fn dup(&self) -> io::Result<Self> {
+        let ret = unsafe { _dup(self.0) };
+        if ret < 0 {
+            return Err(crt_error("_dup", Some(self.0)));
+        }
+
+        Ok(Self(ret))
+    }
+
+    /// Duplicate File Descriptor. (`_dup2`)
+    #[winspawn_macro::ignore_invalid_handler]
+    pub fn dup2(&self, dest: c_int) -> io::Result<Self> {
+        let ret = unsafe { _dup2(self.0, dest) };
+        if ret < 0 {
+            // EBADF is about the source, as for `_dup`
+            return Err(crt_error("_dup2", Some(self.0)));
+        }
+
+        Ok(Self(dest))
+    }
+
+    /// Into a `File` of a duplicate of the handle (`_get_osfhandle`), closing the fd.
+    #[cfg_attr(not(any(feature = "tokio", feature = "async-io")), allow(dead_code))]
+    pub(crate) fn into_file(self) -> io::Result<fs::File> {
+        let handle = osfhandle(self.0).ok_or_else(|| crt_error("_get_osfhandle", Some(self.0)))?;
+        let mut dup = HANDLE::default();
+        unsafe {
+            let process = GetCurrentProcess();
+            DuplicateHandle(
+                process,
+                HANDLE(handle),
+                process,
+                &mut dup,
+                0,
+                false,
+                DUPLICATE_SAME_ACCESS,
+            )
+        }
+        .ok()
+        .map_err(io::Error::other)?;
+        Ok(unsafe { fs::File::from_raw_handle(dup.0 as RawHandle) })
+    }
+
+    #[winspawn_macro::ignore_invalid_handler]
+    pub(crate) fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize) as c_uint;
+        let ret = unsafe { _read(self.0, buf.as_mut_ptr() as *mut c_void, len) };
+        if ret < 0 {
+            return Err(crt_error("_read", Some(self.0)));
+        }
+        Ok(ret as usize)
+    }
+
+    #[winspawn_macro::ignore_invalid_handler]
+    pub(crate) fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(c_int::MAX as usize) as c_uint;
+        let ret = unsafe { _write(self.0, buf.as_ptr() as *const c_void, len) };
+        if ret < 0 {
+            return Err(crt_error("_write", Some(self.0)));
+        }
+        Ok(ret as usize)
+    }
+}
+
+impl Drop for FileDescriptor {
+    #[winspawn_macro::ignore_invalid_handler]
+    fn drop(&mut self) {
+        if unsafe { _close(self.0) } < 0 {
+            // not for a later call under an outer guard
+            invalid::take();
+        }
+    }
+}
+
+/// Create an anonymous pipe. (`_pipe`)
+///
+/// Returns the read end and the write end. `size` is the buffer size, 0 for the
+/// system default.
+#[winspawn_macro::ignore_invalid_handler]
+pub fn pipe(mode: PipeMode, size: u32) -> io::Result<(FileDescriptor, FileDescriptor)> {
+    let mut flags = if mode.is_text() { O_TEXT } else { O_BINARY };
+    if !mode.is_inheritable() {
+        flags |= O_NOINHERIT;
+    }
+
+    let mut fds = [0; 2];
+    let ret = unsafe { _pipe(fds.as_mut_ptr(), size, flags as c_int) };
+    if ret < 0 {
+        return Err(crt_error("_pipe", None));
+    }
+    Ok((FileDescriptor(fds[0]), FileDescriptor(fds[1])))
+}
+
+/// [`Error`] of the CRT call `op` from `errno`.
+///
+/// Call right after `op` fails, with an [`InvalidParameterGuard`] live around it.
+fn crt_error(op: &'static str, fd: Option<c_int>) -> io::Error {
+    let errno = unsafe { *_errno() };
+    log::trace!("{} failed: errno {}", op, errno);
+    match invalid::take() {
+        Some(report) => Error::InvalidParameter {
+            op,
+            fd,
+            errno,
+            report,
+        }
+        .into(),
+        None => Error::from_errno(errno, op, fd).into(),
+    }
+}
+
+/// `_get_osfhandle`. `None` if not open.
+#[winspawn_macro::ignore_invalid_handler]
+fn osfhandle(fd: c_int) -> Option<isize> {
+    let handle = unsafe { _get_osfhandle(fd) };
+    // -2: stdio not associated with a stream
+    if matches!(handle, -1 | -2) {
+        // a closed fd is expected, not a failure of an outer guarded call
+        invalid::take();
+        None
+    } else {
+        Some(handle)
+    }
+}
+
+/// `_dup` but never returns any of `reserved`.
+///
+/// `_dup` returns the lowest free fd, which may be a not yet applied target.
+pub(crate) fn dup_avoiding(
+    fd: &FileDescriptor,
+    reserved: &BTreeSet<c_int>,
+) -> io::Result<FileDescriptor> {
+    let mut parked = vec![];
+    loop {
+        let dup = fd.dup()?;
+        if !reserved.contains(&dup.0) {
+            return Ok(dup);
+        }
+        // hold the slot until found
+        parked.push(dup);
+    }
+}
+
+/// `true` if `fd` is open.
+pub(crate) fn is_open(fd: c_int) -> bool {
+    osfhandle(fd).is_some()
+}
+
+/// Clear the CRT's no-inherit flag, by `_dup2` over itself from a duplicate.
+///
+/// Always `false`: the CRT has no way to set the flag again.
+pub(crate) fn set_inheritable(fd: c_int, reserved: &BTreeSet<c_int>) -> io::Result<bool> {
+    let borrowed = ManuallyDrop::new(unsafe { FileDescriptor::from_raw_fd(fd) });
+    let dup = dup_avoiding(&borrowed, reserved)?;
+    // owned by caller
+    mem::forget(dup.dup2(fd)?);
+    Ok(false)
+}
+
+/// Never called, see [`set_inheritable`].
+pub(crate) fn set_noinherit(_: c_int) -> io::Result<()> {
+    Ok(())
+}
+
+/// Registered wait notifying [`ExitNotify`] when the process exits.
+///
+/// Dropping unregisters the wait, then releases the notify.
+#[derive(Debug)]
+pub(crate) struct Waiter {
+    wait_object: HANDLE,
+    // context of `callback`, at a fixed address until unregistered
+    notify: Box<ExitNotify>,
+}
+
+impl Waiter {
+    fn start(process: HANDLE) -> io::Result<Self> {
+        let notify = Box::new(ExitNotify::new());
+        let mut wait_object = HANDLE::default();
+        unsafe {
+            RegisterWaitForSingleObject(
+                &mut wait_object as *mut _,
+                process,
+                Some(callback),
+                Some(&*notify as *const ExitNotify as *const c_void),
+                INFINITE,
+                WT_EXECUTEINWAITTHREAD | WT_EXECUTEONLYONCE,
+            )
+        }
+        .ok()
+        .map_err(io::Error::other)?;
+        Ok(Self {
+            wait_object,
+            notify,
+        })
+    }
+
+    /// See [`ExitNotify::register`].
+    pub(crate) fn register(&self, waker: &Waker) -> bool {
+        self.notify.register(waker)
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        // waits for a running callback
+        let ret = unsafe { UnregisterWaitEx(self.wait_object, INVALID_HANDLE_VALUE) };
+        if !ret.as_bool() {
+            log::warn!("failed to unregister wait: {}", io::Error::last_os_error());
+            // the callback may still run
+            mem::forget(mem::take(&mut self.notify));
+        }
+    }
+}
+
+/// Represent child process.
+///
+/// An instance is a Future that represents an asynchronous termination.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io;
+///
+/// #[tokio::main(flavor = "current_thread")]
+/// async fn main() -> io::Result<()> {
+///     let mut proc = winspawn::spawn("cargo", ["--version"])?;
+///     let status = proc.await?;
+///     assert!(status.success());
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct Child {
+    proc_handle: HANDLE,
+    pub(crate) waiter: Option<Waiter>,
+    pub(crate) drop_policy: DropPolicy,
+    // ended by `kill`
+    killed: bool,
+    /// Parent end of the child's stdin, if [`Stdio::piped`](crate::Stdio::piped).
+    pub stdin: Option<ChildStdin>,
+    /// Parent end of the child's stdout, if [`Stdio::piped`](crate::Stdio::piped).
+    pub stdout: Option<ChildStdout>,
+    /// Parent end of the child's stderr, if [`Stdio::piped`](crate::Stdio::piped).
+    pub stderr: Option<ChildStderr>,
+}
+
+impl Child {
+    /// Process id.
+    pub fn id(&self) -> u32 {
+        unsafe { GetProcessId(self.proc_handle) }
+    }
+
+    /// Synchronous wait for exit.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        let ret = unsafe { WaitForSingleObject(self.proc_handle, INFINITE) };
+        if ret != WAIT_OBJECT_0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.exit_status()
+    }
+
+    /// Try wait for exit.
+    ///
+    /// Return immediately. If the process is finished, the exit code can be acquired.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        match unsafe { WaitForSingleObject(self.proc_handle, 0) } {
+            WAIT_OBJECT_0 => {}
+            WAIT_TIMEOUT => return Ok(None),
+            _ => return Err(io::Error::last_os_error()),
+        }
+
+        self.exit_status().map(Some)
+    }
+
+    /// Status of the exited process.
+    fn exit_status(&self) -> io::Result<ExitStatus> {
+        let mut code = 0;
+        unsafe { GetExitCodeProcess(self.proc_handle, &mut code) }
+            .ok()
+            .map_err(io::Error::other)?;
+
+        // `TerminateProcess` fails once the process has exited by itself
+        if self.killed {
+            return Ok(ExitStatus::from_kill(code));
+        }
+        Ok(ExitStatus::from_code(code))
+    }
+
+    /// Wait for exit until `deadline`.
+    ///
+    /// Return `None` if the process is still running.
+    pub fn wait_deadline(&mut self, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // round up not to return before the deadline
+            let millis = remaining.as_nanos().div_ceil(1_000_000);
+            let millis = millis.min(INFINITE as u128 - 1) as u32;
+
+            match unsafe { WaitForSingleObject(self.proc_handle, millis) } {
+                WAIT_OBJECT_0 => return self.try_wait(),
+                WAIT_TIMEOUT if Instant::now() >= deadline => return Ok(None),
+                WAIT_TIMEOUT => {}
+                _ => return Err(io::Error::last_os_error()),
+            }
+        }
+    }
+
+    /// Asynchronous wait for exit without consuming `self`.
+    ///
+    /// Dropping the future does not affect the process, so it can be raced with a timer.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::io;
+    /// use std::time::Duration;
+    ///
+    /// #[tokio::main(flavor = "current_thread")]
+    /// async fn main() -> io::Result<()> {
+    ///     let mut proc = winspawn::spawn("python", ["-c", "import time; time.sleep(60)"])?;
+    ///     let result = tokio::time::timeout(Duration::from_millis(100), proc.wait_async()).await;
+    ///     assert!(result.is_err());
+    ///     proc.kill()?;
+    ///     assert!(!proc.wait_async().await?.success());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn wait_async(&mut self) -> Wait<'_> {
+        Wait(self)
+    }
+
+    /// Terminate process. (`TerminateProcess`)
+    ///
+    /// Windows has no signals: the process exits with code 1, and the exit status of
+    /// this `Child` reports [`ExitStatus::killed`] with `code()` `None`, as `SIGKILL`
+    /// does on Unix. Other handles to the process, including a `Child` made by
+    /// `from_raw_handle`, only see exit code 1.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::io;
+    /// use winspawn::spawn;
+    ///
+    /// fn main() -> io::Result<()> {
+    ///     let mut proc = spawn("python", ["-c", r#"import time; time.sleep(0xFFFFFFFF)"#])?;
+    ///     proc.kill()?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn kill(&mut self) -> io::Result<()> {
+        unsafe { TerminateProcess(self.proc_handle, 1) }
+            .ok()
+            .map_err(io::Error::other)?;
+        self.killed = true;
+        Ok(())
+    }
+
+    /// Start watching on the first poll.
+    pub(crate) fn waiter(&mut self) -> io::Result<&Waiter> {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => Waiter::start(self.proc_handle)?,
+        };
+        Ok(self.waiter.insert(waiter))
+    }
+
+    /// Post `WM_CLOSE` to every top-level window of the process.
+    ///
+    /// A console process without window gets nothing.
+    pub(crate) fn request_stop(&mut self) -> io::Result<()> {
+        unsafe extern "system" fn close(hwnd: HWND, pid: LPARAM) -> BOOL {
+            let mut owner = 0;
+            GetWindowThreadProcessId(hwnd, Some(&mut owner));
+            if owner as isize == pid.0 {
+                PostMessageW(hwnd, WM_CLOSE, WPARAM(0), LPARAM(0));
+            }
+            true.into()
+        }
+
+        let pid = unsafe { GetProcessId(self.proc_handle) };
+        if pid == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { EnumWindows(Some(close), LPARAM(pid as isize)) }
+            .ok()
+            .map_err(io::Error::other)
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        self.stop_on_drop();
+        // stop waiting before the handle is closed
+        drop(self.waiter.take());
+        unsafe { CloseHandle(self.proc_handle) };
+    }
+}
+
+impl AsRawHandle for Child {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.proc_handle.0 as RawHandle
+    }
+}
+
+impl IntoRawHandle for Child {
+    /// The drop policy is not applied.
+    fn into_raw_handle(self) -> RawHandle {
+        let mut this = ManuallyDrop::new(self);
+        drop(this.waiter.take());
+        drop((this.stdin.take(), this.stdout.take(), this.stderr.take()));
+        this.proc_handle.0 as RawHandle
+    }
+}
+
+impl FromRawHandle for Child {
+    /// `handle` must be an owned process handle with `SYNCHRONIZE`,
+    /// `PROCESS_QUERY_LIMITED_INFORMATION` and `PROCESS_TERMINATE` access.
+    unsafe fn from_raw_handle(handle: RawHandle) -> Self {
+        Self {
+            proc_handle: HANDLE(handle as isize),
+            waiter: None,
+            drop_policy: DropPolicy::default(),
+            killed: false,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+        }
+    }
+}
+
+unsafe extern "system" fn callback(ptr: *mut c_void, _: BOOLEAN) {
+    // owned by the `Waiter`, which unregisters before dropping it
+    let notify = &*(ptr as *const ExitNotify);
+    notify.notify();
+}
+
+fn enc_wstr<S: AsRef<OsStr>>(s: S) -> Vec<wchar_t> {
+    s.as_ref().encode_wide().chain(iter::once(0)).collect()
+}
+
+/// call `_spawnlp`.
+///
+/// All File Descriptors that do not have the O_NOINHERIT flag will be inherited by the child process.
+pub fn spawn<P, A, AS>(program: P, args: A) -> io::Result<Child>
+where
+    P: AsRef<OsStr>,
+    A: IntoIterator<Item = AS>,
+    AS: AsRef<OsStr>,
+{
+    Command::new(program).args(args).spawn()
+}
+
+/// Upper bound of CRT fds. (`_NHANDLE_`)
+const MAX_FDS: c_int = 8192;
+
+/// The CRT fd table grows by this many fds. (`IOINFO_ARRAY_ELTS`)
+const FD_BLOCK: c_int = 64;
+
+/// Size of the CRT fd table. (`_nhandle`)
+///
+/// `_isatty` checks only that `fd` is in the table, not that it is open, so it reports
+/// an invalid parameter at the first fd past the end.
+#[winspawn_macro::ignore_invalid_handler]
+fn fd_table_size() -> c_int {
+    invalid::clear();
+    (0..MAX_FDS)
+        .step_by(FD_BLOCK as usize)
+        .find(|fd| {
+            unsafe { _isatty(*fd) };
+            invalid::take().is_some()
+        })
+        .unwrap_or(MAX_FDS)
+}
+
+/// [`direct::Backend`] with `CreateProcessW`.
+#[derive(Debug)]
+struct CreateProcess;
+
+impl direct::Backend for CreateProcess {
+    type Process = Child;
+
+    fn open_fds(&mut self) -> io::Result<Vec<c_int>> {
+        Ok((0..fd_table_size())
+            .filter(|fd| osfhandle(*fd).is_some())
+            .collect())
+    }
+
+    fn query(&mut self, fd: c_int) -> io::Result<Option<(Flags, isize)>> {
+        let handle = match osfhandle(fd) {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+
+        let mut flags = match unsafe { GetFileType(HANDLE(handle)) } {
+            FILE_TYPE_PIPE => Flags::FOPEN | Flags::FPIPE,
+            FILE_TYPE_CHAR => Flags::FOPEN | Flags::FDEV,
+            _ => Flags::FOPEN,
+        };
+        // the CRT's own flag is not public. a non-inheritable handle is of no use to the child.
+        let mut info = 0;
+        let ret = unsafe { GetHandleInformation(HANDLE(handle), &mut info) };
+        if ret.as_bool() && info & HANDLE_FLAG_INHERIT.0 == 0 {
+            flags |= Flags::FNOINHERIT;
+        }
+        Ok(Some((flags, handle)))
+    }
+
+    fn duplicate_inheritable(&mut self, handle: isize) -> io::Result<isize> {
+        let mut dup = HANDLE::default();
+        unsafe {
+            let process = GetCurrentProcess();
+            DuplicateHandle(
+                process,
+                HANDLE(handle),
+                process,
+                &mut dup,
+                0,
+                true,
+                DUPLICATE_SAME_ACCESS,
+            )
+        }
+        .ok()
+        .map_err(io::Error::other)?;
+        Ok(dup.0)
+    }
+
+    fn close(&mut self, handle: isize) {
+        let ret = unsafe { CloseHandle(HANDLE(handle)) };
+        if !ret.as_bool() {
+            log::warn!("failed to close handle: {}", io::Error::last_os_error());
+        }
+    }
+
+    fn create_process(&mut self, startup: &Startup<'_>) -> io::Result<Child> {
+        let application = startup.application.as_ref().map(enc_wstr);
+        let mut command_line = startup.command_line.clone();
+        command_line.push(0);
+
+        let env = startup.env.as_deref().map(env_block);
+        let current_dir = startup.current_dir.map(enc_wstr);
+
+        let mut size = 0;
+        // fails with ERROR_INSUFFICIENT_BUFFER, returns required size.
+        unsafe {
+            InitializeProcThreadAttributeList(
+                LPPROC_THREAD_ATTRIBUTE_LIST::default(),
+                1,
+                0,
+                &mut size,
+            )
+        };
+        let mut buf = vec![0usize; size.div_ceil(mem::size_of::<usize>())];
+        let attrs = ProcThreadAttributeList::new(&mut buf, size)?;
+
+        if !startup.handles.is_empty() {
+            unsafe {
+                UpdateProcThreadAttribute(
+                    attrs.0,
+                    0,
+                    PROC_THREAD_ATTRIBUTE_HANDLE_LIST as usize,
+                    Some(startup.handles.as_ptr() as *const c_void),
+                    startup.handles.len() * mem::size_of::<HANDLE>(),
+                    None,
+                    None,
+                )
+            }
+            .ok()
+            .map_err(io::Error::other)?;
+        }
+
+        let mut info = STARTUPINFOEXW::default();
+        info.StartupInfo.cb = mem::size_of::<STARTUPINFOEXW>() as u32;
+        info.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
+        info.StartupInfo.hStdInput = HANDLE(startup.stdio[0]);
+        info.StartupInfo.hStdOutput = HANDLE(startup.stdio[1]);
+        info.StartupInfo.hStdError = HANDLE(startup.stdio[2]);
+        info.StartupInfo.cbReserved2 = startup.reserved2.len() as u16;
+        info.StartupInfo.lpReserved2 = startup.reserved2.as_ptr() as *mut u8;
+        info.lpAttributeList = attrs.0;
+
+        let mut flags = EXTENDED_STARTUPINFO_PRESENT;
+        if env.is_some() {
+            flags |= CREATE_UNICODE_ENVIRONMENT;
+        }
+
+        let mut proc_info = PROCESS_INFORMATION::default();
+        unsafe {
+            CreateProcessW(
+                application
+                    .as_ref()
+                    .map(|app| PCWSTR(app.as_ptr()))
+                    .unwrap_or_else(PCWSTR::null),
+                PWSTR(command_line.as_mut_ptr()),
+                None,
+                None,
+                !startup.handles.is_empty(),
+                flags,
+                env.as_ref().map(|env| env.as_ptr() as *const c_void),
+                current_dir
+                    .as_ref()
+                    .map(|dir| PCWSTR(dir.as_ptr()))
+                    .unwrap_or_else(PCWSTR::null),
+                &info.StartupInfo,
+                &mut proc_info,
+            )
+        }
+        .ok()
+        .map_err(io::Error::other)?;
+
+        unsafe { CloseHandle(proc_info.hThread) };
+        Ok(Child {
+            proc_handle: proc_info.hProcess,
+            waiter: None,
+            drop_policy: DropPolicy::default(),
+            killed: false,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+        })
+    }
+}
+
+struct ProcThreadAttributeList(LPPROC_THREAD_ATTRIBUTE_LIST);
+
+impl ProcThreadAttributeList {
+    fn new(buf: &mut [usize], mut size: usize) -> io::Result<Self> {
+        let list = LPPROC_THREAD_ATTRIBUTE_LIST(buf.as_mut_ptr() as *mut c_void);
+        unsafe { InitializeProcThreadAttributeList(list, 1, 0, &mut size) }
+            .ok()
+            .map_err(io::Error::other)?;
+        Ok(Self(list))
+    }
+}
+
+impl Drop for ProcThreadAttributeList {
+    fn drop(&mut self) {
+        unsafe { DeleteProcThreadAttributeList(self.0) };
+    }
+}
+
+pub(crate) fn spawn_command(cmd: &Command<'_>, fds: &FdMap<'_>) -> io::Result<Child> {
+    if cmd.is_lock_free() {
+        return direct::spawn(&mut CreateProcess, cmd, fds, Inherit::Stdio);
+    }
+    // `_wspawnvp` only runs in the parent's current directory, and would swap the
+    // parent's own stdio meanwhile
+    if cmd.get_current_dir().is_some() || fds.iter().any(|(dest, _)| dest < 3) {
+        // the fd table is read as a whole, as `_wspawnvp` does under the lock
+        let _lock = StaticMutex::acquire();
+        return direct::spawn(&mut CreateProcess, cmd, fds, Inherit::All);
+    }
+
+    // the CRT joins argv with spaces without quoting. pass the whole line as one.
+    let (application, mut command_line) = cmd.windows_command_line(false)?;
+    command_line.push(0);
+    let program = enc_wstr(application.as_deref().unwrap_or(cmd.get_program()));
+    let args = vec![command_line];
+    log::trace!("prog: {:x?}", program);
+    log::trace!("args: {:x?}", args);
+    let args = args
+        .iter()
+        .map(Vec::as_ptr)
+        .chain(iter::once(ptr::null()))
+        .collect::<Vec<_>>();
+
+    let env = cmd.capture_env().map(|env| {
+        env.into_iter()
+            .map(|(mut key, val)| {
+                key.push("=");
+                key.push(val);
+                enc_wstr(key)
+            })
+            .collect::<Vec<_>>()
+    });
+    let envp = env.as_ref().map(|env| {
+        env.iter()
+            .map(Vec::as_ptr)
+            .chain(iter::once(ptr::null()))
+            .collect::<Vec<_>>()
+    });
+
+    fds.apply(|| {
+        // an invalid argument fails with errno instead of ending the process
+        let _guard = InvalidParameterGuard::new();
+        // the report of an outer guard is not about this call
+        invalid::clear();
+        let child = unsafe {
+            match &envp {
+                Some(envp) => _wspawnvpe(
+                    P_NOWAIT as c_int,
+                    program.as_ptr(),
+                    args.as_ptr(),
+                    envp.as_ptr(),
+                ),
+                None => _wspawnvp(P_NOWAIT as c_int, program.as_ptr(), args.as_ptr()),
+            }
+        };
+        if child < 0 {
+            let op = if envp.is_some() {
+                "_wspawnvpe"
+            } else {
+                "_wspawnvp"
+            };
+            return Err(crt_error(op, None));
+        }
+
+        Ok(Child {
+            proc_handle: HANDLE(child),
+            waiter: None,
+            drop_policy: DropPolicy::default(),
+            killed: false,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kill_status() {
+        let mut child = spawn("python", ["-c", "import time; time.sleep(10)"]).unwrap();
+        child.kill().unwrap();
+        let status = child.wait().unwrap();
+        assert!(status.killed(), "{}", status);
+        assert!(!status.success());
+        assert_eq!(None, status.code());
+        assert_eq!(None, status.ntstatus());
+
+        // an ordinary exit with code 1 is not a kill
+        let status = spawn("python", ["-c", "raise SystemExit(1)"])
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(!status.killed());
+        assert_eq!(Some(1), status.code());
+    }
+
+    #[test]
+    fn test_spawn_not_found() {
+        let (rx, _tx) = pipe(PipeMode::new(), 0).unwrap();
+        // probing the not yet open fd 3 reports an invalid parameter
+        let err = Command::new("winspawn-missing-program")
+            .fd(3, &rx)
+            .spawn()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind(), "{}", err);
+        let inner = err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert!(
+            matches!(inner, Error::ProgramNotFound { op, .. } if op.starts_with("_wspawnvp")),
+            "{:?}",
+            inner
+        );
+    }
+
+    #[test]
+    fn test_dup2_names_source() {
+        let (rx, tx) = pipe(PipeMode::new(), 0).unwrap();
+        let closed = ManuallyDrop::new(unsafe { FileDescriptor::from_raw_fd(rx.as_raw_fd()) });
+        drop(rx);
+        let err = closed.dup2(tx.as_raw_fd()).unwrap_err();
+        let inner = err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert_eq!(Some(closed.as_raw_fd()), inner.fd(), "{:?}", inner);
+    }
+
+    #[test]
+    fn test_pipe() {
+        let (rx, tx) = pipe(PipeMode::new(), 0).unwrap();
+        let mut info = 0;
+        let handle = osfhandle(rx.as_raw_fd()).unwrap();
+        assert!(unsafe { GetHandleInformation(HANDLE(handle), &mut info) }.as_bool());
+        assert_eq!(0, info & HANDLE_FLAG_INHERIT.0);
+        drop((rx, tx));
+
+        let (rx, _tx) = pipe(PipeMode::new().inheritable(true), 4096).unwrap();
+        let handle = osfhandle(rx.as_raw_fd()).unwrap();
+        assert!(unsafe { GetHandleInformation(HANDLE(handle), &mut info) }.as_bool());
+        assert_ne!(0, info & HANDLE_FLAG_INHERIT.0);
+    }
+}
@@ -0,0 +1,24 @@
+//! Helpers shared by the integration tests.
+
+#[cfg(windows)]
+pub use tokio_anon_pipe::anon_pipe;
+
+/// Pipe ends are already async on Windows.
+#[cfg(windows)]
+pub fn parent_end<T>(end: T) -> T {
+    end
+}
+
+/// Close-on-exec pipe. Only fds moved by winspawn are inherited.
+#[cfg(unix)]
+pub async fn anon_pipe() -> std::io::Result<(std::fs::File, std::fs::File)> {
+    let (rx, tx) = std::io::pipe()?;
+    let rx = std::os::fd::OwnedFd::from(rx).into();
+    let tx = std::os::fd::OwnedFd::from(tx).into();
+    Ok((rx, tx))
+}
+
+#[cfg(unix)]
+pub fn parent_end(end: std::fs::File) -> tokio::fs::File {
+    tokio::fs::File::from_std(end)
+}
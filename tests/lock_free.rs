@@ -0,0 +1,39 @@
+#![cfg(windows)]
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use winspawn::{Command, FileDescriptor, Mode};
+
+#[tokio::test]
+async fn test_lock_free() {
+    pretty_env_logger::init();
+
+    let (rxtheir, mut txme) = tokio_anon_pipe::anon_pipe().await.unwrap();
+    let (mut rxme, txtheir) = tokio_anon_pipe::anon_pipe().await.unwrap();
+
+    let rxtheir = FileDescriptor::from_raw_handle(rxtheir, Mode::ReadOnly).unwrap();
+    let txtheir = FileDescriptor::from_raw_handle(txtheir, Mode::ReadWrite).unwrap();
+
+    let prog = Command::new("python")
+        .arg("./tests/test.py")
+        .fd(3, &rxtheir)
+        .fd(4, &txtheir)
+        .lock_free(true)
+        .spawn()
+        .unwrap();
+    drop(rxtheir);
+    drop(txtheir);
+
+    // poll process exit
+    let task = tokio::spawn(prog);
+
+    txme.write_all(b"Hello").await.unwrap();
+    txme.shutdown().await.unwrap();
+    drop(txme);
+
+    let mut buf = vec![];
+    rxme.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(b"Hello".as_ref(), &buf);
+
+    let status = task.await.unwrap().unwrap();
+    assert!(status.success(), "{}", status);
+}
@@ -1,25 +1,32 @@
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use winspawn::{move_fd, spawn, FileDescriptor, Mode};
+use winspawn::{spawn, FdMap, FileDescriptor, Mode};
+
+mod common;
+
+use common::{anon_pipe, parent_end};
 
 #[tokio::test]
 async fn test_simple() {
     pretty_env_logger::init();
 
-    let (rxtheir, mut txme) = tokio_anon_pipe::anon_pipe().await.unwrap();
-    let (mut rxme, txtheir) = tokio_anon_pipe::anon_pipe().await.unwrap();
+    let (rxtheir, txme) = anon_pipe().await.unwrap();
+    let mut txme = parent_end(txme);
+    let (rxme, txtheir) = anon_pipe().await.unwrap();
+    let mut rxme = parent_end(rxme);
     eprintln!("{:?}", rxtheir);
     eprintln!("{:?}", txtheir);
 
     let rxtheir = FileDescriptor::from_raw_handle(rxtheir, Mode::ReadOnly).unwrap();
     let txtheir = FileDescriptor::from_raw_handle(txtheir, Mode::ReadWrite).unwrap();
 
-    let prog = move_fd(&rxtheir, 3, |_| {
-        move_fd(&txtheir, 4, |_| {
+    let prog = FdMap::new()
+        .insert(3, &rxtheir)
+        .insert(4, &txtheir)
+        .apply(|| {
             eprintln!("spawn");
             spawn("python", ["./tests/test.py"])
         })
-    })
-    .unwrap();
+        .unwrap();
     drop(rxtheir);
     drop(txtheir);
 
@@ -38,6 +45,6 @@ async fn test_simple() {
     assert_eq!(b"Hello".as_ref(), &buf);
     eprintln!("OK");
 
-    let exitcode = task.await.unwrap().unwrap();
-    assert_eq!(0, exitcode);
+    let status = task.await.unwrap().unwrap();
+    assert!(status.success(), "{}", status);
 }
@@ -1,6 +1,10 @@
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use winspawn::{move_fd, spawn, FileDescriptor, Mode};
 
+mod common;
+
+use common::{anon_pipe, parent_end};
+
 #[tokio::test]
 async fn test_twice() {
     pretty_env_logger::init();
@@ -10,8 +14,10 @@ async fn test_twice() {
 }
 
 async fn proc() {
-    let (rxtheir, mut txme) = tokio_anon_pipe::anon_pipe().await.unwrap();
-    let (mut rxme, txtheir) = tokio_anon_pipe::anon_pipe().await.unwrap();
+    let (rxtheir, txme) = anon_pipe().await.unwrap();
+    let mut txme = parent_end(txme);
+    let (rxme, txtheir) = anon_pipe().await.unwrap();
+    let mut rxme = parent_end(rxme);
     eprintln!("{:?}", rxtheir);
     eprintln!("{:?}", txtheir);
 
@@ -43,6 +49,6 @@ async fn proc() {
     assert_eq!(b"Hello".as_ref(), &buf);
     eprintln!("OK");
 
-    let exitcode = task.await.unwrap().unwrap();
-    assert_eq!(0, exitcode);
+    let status = task.await.unwrap().unwrap();
+    assert!(status.success(), "{}", status);
 }
@@ -0,0 +1,6 @@
+#[winspawn::ignore_invalid_handler(restore = false)]
+fn f() {}
+
+fn main() {
+    f();
+}
@@ -0,0 +1,4 @@
+#[winspawn::ignore_invalid_handler]
+struct S;
+
+fn main() {}
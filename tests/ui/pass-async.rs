@@ -0,0 +1,26 @@
+use std::io;
+
+struct Source(Vec<u8>);
+
+impl Source {
+    #[winspawn::ignore_invalid_handler]
+    async fn first(&self) -> io::Result<u8> {
+        tokio::task::yield_now().await;
+        let byte = self.0.first().copied();
+        byte.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+}
+
+#[winspawn::ignore_invalid_handler]
+async fn sum(source: Source) -> io::Result<u32> {
+    let first = source.first().await?;
+    Ok(first as u32 + source.0.len() as u32)
+}
+
+fn main() {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    assert_eq!(5, rt.block_on(sum(Source(vec![3, 4]))).unwrap());
+    assert!(rt.block_on(sum(Source(vec![]))).is_err());
+}
@@ -0,0 +1,50 @@
+// no `wchar_t`, `c_uint` nor the CRT functions in scope
+use std::io;
+use std::panic;
+
+#[winspawn::ignore_invalid_handler]
+fn parse(s: &str) -> io::Result<u8> {
+    if s.is_empty() {
+        return Ok(0);
+    }
+    s.parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+#[winspawn::ignore_invalid_handler]
+fn explode() {
+    panic!("boom");
+}
+
+struct Counter(u32);
+
+impl Counter {
+    #[winspawn::ignore_invalid_handler]
+    fn get(&self) -> u32 {
+        self.0
+    }
+
+    #[winspawn::ignore_invalid_handler]
+    fn incr(&mut self) -> &mut Self {
+        self.0 += 1;
+        self
+    }
+
+    #[winspawn::ignore_invalid_handler]
+    fn into_inner(self) -> u32 {
+        self.0
+    }
+}
+
+fn main() {
+    assert_eq!(0, parse("").unwrap());
+    assert_eq!(12, parse("12").unwrap());
+    assert!(parse("x").is_err());
+
+    assert!(panic::catch_unwind(explode).is_err());
+
+    let mut counter = Counter(0);
+    counter.incr().incr();
+    assert_eq!(2, counter.get());
+    assert_eq!(2, counter.into_inner());
+}
@@ -1,38 +1,94 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 
 use quote::quote;
-use syn::{parse_macro_input, ItemFn};
+use syn::{Error, ItemFn};
 
+/// Install the CRT invalid parameter handler of `winspawn` while the function runs.
+///
+/// A CRT call given an invalid argument then fails with `errno` instead of aborting,
+/// and the report is kept for `winspawn::Error::InvalidParameter`. The previous
+/// handler is restored on return, on `?` and on panic.
+///
+/// Applies to functions and methods. For an `async fn` the handler is installed around
+/// each poll, as the body may resume on another thread. An attribute on a closure is
+/// not stable Rust, so wrap the closure body in
+/// `winspawn::invalid::with_invalid_parameter_handler`.
+///
+/// Expands to paths under `::winspawn`, so the crate using it must depend on `winspawn`.
 #[proc_macro_attribute]
-pub fn ignore_invalid_handler(_: TokenStream, item: TokenStream) -> TokenStream {
-    // TODO symbol resolve rule
-    let fun = parse_macro_input!(item as ItemFn);
+pub fn ignore_invalid_handler(attr: TokenStream, item: TokenStream) -> TokenStream {
+    expand(attr.into(), item.into())
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(attr: TokenStream2, item: TokenStream2) -> syn::Result<TokenStream2> {
+    if !attr.is_empty() {
+        return Err(Error::new_spanned(
+            attr,
+            "`ignore_invalid_handler` takes no arguments",
+        ));
+    }
+    match syn::parse2::<ItemFn>(item.clone()) {
+        Ok(fun) => Ok(expand_fn(fun)),
+        Err(_) => Err(Error::new_spanned(
+            item,
+            "`ignore_invalid_handler` applies to `fn`, use `with_invalid_parameter_handler` for a closure",
+        )),
+    }
+}
 
+fn expand_fn(fun: ItemFn) -> TokenStream2 {
     let ItemFn {
         attrs,
         vis,
         sig,
         block,
     } = fun;
-    let result = quote! {
+
+    if sig.asyncness.is_some() {
+        return quote! {
+            #(#attrs)*
+            #vis #sig {
+                ::winspawn::__private::Guarded::new(async move #block).await
+            }
+        };
+    }
+
+    quote! {
         #(#attrs)*
         #vis #sig {
-            unsafe extern "C" fn handler(_: *const wchar_t, _: *const wchar_t, _: *const wchar_t, _: c_uint, _: usize) {}
+            let _guard = ::winspawn::__private::InvalidParameterGuard::new();
+            #block
+        }
+    }
+}
 
-            let old_handler = unsafe {
-                _set_thread_local_invalid_parameter_handler(Some(handler))
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let r = #block;
+    fn expand_str(attr: &str, item: &str) -> syn::Result<String> {
+        expand(attr.parse().unwrap(), item.parse().unwrap()).map(|tokens| tokens.to_string())
+    }
 
-            unsafe {
-                _set_thread_local_invalid_parameter_handler(old_handler)
-            };
+    #[test]
+    fn test_fn() {
+        let out = expand_str("", "fn f(&self) -> u8 { 1 }").unwrap();
+        assert!(out.contains(":: winspawn :: __private :: InvalidParameterGuard :: new ()"));
+        assert!(out.starts_with("fn f (& self) -> u8"), "{}", out);
 
-            r
-        }
-    };
-    result.into()
+        let out = expand_str("", "async fn f() { g().await }").unwrap();
+        assert!(out.contains("Guarded :: new (async move {"), "{}", out);
+    }
+
+    #[test]
+    fn test_rejected() {
+        assert!(expand_str("x", "fn f() {}").is_err());
+        assert!(expand_str("", "struct S;").is_err());
+        assert!(expand_str("", "move |x: u8| x + 1").is_err());
+    }
 }